@@ -1,9 +1,21 @@
-use escpos::{driver::SerialPortDriver, printer::Printer, utils::*};
-use image::{ImageBuffer, Rgb, RgbImage, GrayImage, Luma};
-use imageproc::drawing::{draw_text_mut, text_size};
-use ab_glyph::{FontRef, PxScale};
-use ar_reshaper::reshape_line;
-use serde::Deserialize;
+use escpos::driver::{Driver, NetworkDriver, UsbDriver};
+use image::{imageops::FilterType, ImageBuffer, Rgb, RgbImage, GrayImage, Luma};
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut, text_size};
+use imageproc::rect::Rect;
+use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
+use ar_reshaper::{ArabicReshaper, Language, ReshaperConfig};
+use qrcode::{Color, EcLevel, QrCode, Version};
+use pdf_writer::{Content, Filter, Name, Pdf, Rect as PdfRect, Ref};
+use unicode_bidi::{bidi_class, BidiClass, BidiInfo, Level};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chrono::{Datelike, Timelike};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // ================================================================
 // Arabic receipt (ESC * 24-dot) — RTL, crisp (NCR 7197)
@@ -14,6 +26,104 @@ use serde::Deserialize;
 
 const DEFAULT_COM_PORT: &str = "COM7";
 const DEFAULT_BAUD_RATE: u32 = 9600;
+const DEFAULT_NET_PORT: u16 = 9100;
+const NET_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_OPEN_RETRIES: u32 = 2;
+const DEFAULT_OPEN_RETRY_MS: u64 = 300;
+const DEFAULT_WRITE_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_QUEUE_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_CLOSE_DELAY_MS: u64 = 100;
+const STATUS_TIMEOUT: Duration = Duration::from_millis(500);
+const SERIAL_TIMEOUT: Duration = Duration::from_secs(5);
+const BEEP_ON_PRINT_TIMES: u8 = 2;
+const BEEP_ON_PRINT_MS: u16 = 100;
+const MAX_RECEIPT_HEIGHT: u32 = 65_535; // GS v 0 row limit, about 8 m of paper at 203 dpi
+
+/// One printer: `{"transport": "serial", "port": "COM7", "baud": 9600, "parity": "none"}`,
+/// `{"transport": "network", "host": "192.168.1.50", "port": 9100}` or
+/// `{"transport": "usb", "vid": "0x04b8", "pid": "0x0202"}`.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "transport", rename_all = "snake_case")]
+enum Target {
+    Serial {
+        port: String,
+        #[serde(default = "default_baud_rate")]
+        baud: u32,
+        #[serde(flatten)]
+        settings: SerialSettings,
+    },
+    Network {
+        host: String,
+        #[serde(default = "default_net_port")]
+        port: u16,
+    },
+    Usb {
+        #[serde(deserialize_with = "de_usb_id")]
+        vid: u16,
+        #[serde(deserialize_with = "de_usb_id")]
+        pid: u16,
+    },
+}
+
+/// Serial framing beyond the baud rate; the defaults are 8N1 without flow control.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+struct SerialSettings {
+    data_bits: u8, // 5-8
+    parity: SerialParity,
+    stop_bits: u8, // 1 or 2
+    flow_control: SerialFlow,
+}
+impl Default for SerialSettings {
+    fn default() -> Self {
+        Self { data_bits: 8, parity: SerialParity::None, stop_bits: 1, flow_control: SerialFlow::None }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SerialParity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SerialFlow {
+    #[default]
+    None,
+    Software, // XON/XOFF
+    Hardware, // RTS/CTS
+}
+
+fn default_baud_rate() -> u32 { DEFAULT_BAUD_RATE }
+fn default_net_port() -> u16 { DEFAULT_NET_PORT }
+fn de_usb_id<'de, D: serde::Deserializer<'de>>(d: D) -> Result<u16, D::Error> {
+    let raw = String::deserialize(d)?;
+    parse_usb_id("usb id", &raw).map_err(serde::de::Error::custom)
+}
+
+/// The printer configured through the environment (`PRINTER_TRANSPORT` and friends).
+fn get_target() -> Result<Target, String> {
+    let serial = || Target::Serial { port: get_com_port(), baud: get_baud_rate(), settings: SerialSettings::default() };
+    let usb = || -> Result<Target, String> {
+        let (vid, pid) = get_usb_ids()?.ok_or("PRINTER_USB_VID / PRINTER_USB_PID are not set")?;
+        Ok(Target::Usb { vid, pid })
+    };
+    match std::env::var("PRINTER_TRANSPORT").map(|s| s.trim().to_lowercase()) {
+        // No explicit transport: USB when its IDs are configured, serial otherwise.
+        Err(_) => if get_usb_ids()?.is_some() { usb() } else { Ok(serial()) },
+        Ok(s) if s.is_empty() || s == "serial" => Ok(serial()),
+        Ok(s) if s == "network" => {
+            let host = get_printer_host().ok_or("PRINTER_HOST is not set")?;
+            Ok(Target::Network { host, port: get_printer_port() })
+        }
+        Ok(s) if s == "usb" => usb(),
+        Ok(s) => Err(format!("unknown PRINTER_TRANSPORT '{}' (expected serial, network or usb)", s)),
+    }
+}
 
 fn get_com_port() -> String {
     std::env::var("PRINTER_COM_PORT").unwrap_or_else(|_| DEFAULT_COM_PORT.to_string())
@@ -21,6 +131,76 @@ fn get_com_port() -> String {
 fn get_baud_rate() -> u32 {
     std::env::var("PRINTER_BAUD_RATE").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_BAUD_RATE)
 }
+fn get_open_retries() -> u32 {
+    std::env::var("PRINTER_OPEN_RETRIES").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_OPEN_RETRIES)
+}
+fn get_open_retry_delay() -> Duration {
+    let ms = std::env::var("PRINTER_OPEN_RETRY_MS").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_OPEN_RETRY_MS);
+    Duration::from_millis(ms)
+}
+/// Upper bound for a single write (one band) before the job is abandoned.
+fn get_write_timeout() -> Duration {
+    let ms = std::env::var("PRINTER_WRITE_TIMEOUT_MS").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_WRITE_TIMEOUT_MS);
+    Duration::from_millis(ms)
+}
+/// How long a job waits for the previous one on the same printer before giving up.
+fn get_queue_timeout() -> Duration {
+    let ms = std::env::var("PRINTER_QUEUE_TIMEOUT_MS").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_QUEUE_TIMEOUT_MS);
+    Duration::from_millis(ms)
+}
+/// Pause after closing the port, before the next job may open it.
+fn get_close_delay() -> Duration {
+    let ms = std::env::var("PRINTER_CLOSE_DELAY_MS").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_CLOSE_DELAY_MS);
+    Duration::from_millis(ms)
+}
+fn get_printer_host() -> Option<String> {
+    std::env::var("PRINTER_HOST").ok().filter(|s| !s.trim().is_empty())
+}
+fn get_printer_port() -> u16 {
+    std::env::var("PRINTER_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_NET_PORT)
+}
+/// File that receives a copy of every job's ESC/POS bytes (for diffing output in CI).
+fn get_dump_path() -> Option<String> {
+    std::env::var("PRINTER_DUMP_PATH").ok().filter(|s| !s.trim().is_empty())
+}
+/// File that gets one JSON line per receipt job, printed or failed (audit trail).
+fn get_log_path() -> Option<String> {
+    std::env::var("PRINTER_LOG_PATH").ok().filter(|s| !s.trim().is_empty())
+}
+/// Directory of saved layout presets: `PRINTER_PRESETS_DIR`, else `layouts/` in the
+/// app's config directory (where Tauri's `app_config_dir` points).
+fn get_presets_dir() -> Result<std::path::PathBuf, String> {
+    if let Some(dir) = std::env::var_os("PRINTER_PRESETS_DIR").filter(|s| !s.is_empty()) {
+        return Ok(dir.into());
+    }
+    let config = dirs::config_dir().ok_or("no config directory on this system")?;
+    Ok(config.join(APP_IDENTIFIER).join("layouts"))
+}
+fn parse_usb_id(var: &str, raw: &str) -> Result<u16, String> {
+    let t = raw.trim();
+    let hex = t.strip_prefix("0x").or_else(|| t.strip_prefix("0X")).unwrap_or(t);
+    u16::from_str_radix(hex, 16).map_err(|_| format!("{} '{}' is not a hex id like 0x04b8", var, raw))
+}
+/// `(vendor, product)` from `PRINTER_USB_VID` / `PRINTER_USB_PID`, if both are set.
+fn get_usb_ids() -> Result<Option<(u16, u16)>, String> {
+    let vid = std::env::var("PRINTER_USB_VID").ok().filter(|s| !s.trim().is_empty());
+    let pid = std::env::var("PRINTER_USB_PID").ok().filter(|s| !s.trim().is_empty());
+    match (vid, pid) {
+        (None, None) => Ok(None),
+        (Some(v), Some(p)) => Ok(Some((parse_usb_id("PRINTER_USB_VID", &v)?, parse_usb_id("PRINTER_USB_PID", &p)?))),
+        _ => Err("PRINTER_USB_VID and PRINTER_USB_PID must be set together".to_string()),
+    }
+}
+/// Number of attached USB devices with this vendor/product pair (0 if enumeration fails).
+fn count_usb_devices(vid: u16, pid: u16) -> usize {
+    rusb::devices()
+        .map(|list| {
+            list.iter()
+                .filter(|d| d.device_descriptor().map(|dd| dd.vendor_id() == vid && dd.product_id() == pid).unwrap_or(false))
+                .count()
+        })
+        .unwrap_or(0)
+}
 fn normalize_com_port(port: &str) -> String {
     #[cfg(windows)]
     {
@@ -37,42 +217,654 @@ fn normalize_com_port(port: &str) -> String {
 
 // ---------------- Data & Layout ----------------
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 struct Item {
     name: String,
+    #[serde(alias = "qty", deserialize_with = "de_qty")]
     qty_str: String,   // printed exactly as provided
     price: f32,
     total: f32,        // printed as provided
+    #[serde(default)]
+    discount: Option<f32>, // per-line discount, taken off `total`
+    #[serde(default)]
+    tax_rate: Option<f32>, // VAT fraction for this line; None = the receipt's `tax_rate`
+    #[serde(default)]
+    notes: Vec<String>,    // modifiers ("بدون بصل"), each on its own smaller line under the name
+    #[serde(default)]
+    unit: Option<Unit>,    // how the quantity is printed; None = exactly as provided
+    #[serde(default)]
+    sku: Option<String>,   // product code, printed small under the name with `Layout.show_sku`
+    #[serde(default)]
+    force_ltr: bool,       // draw the name as one LTR run (brand names like "Coca Cola (250)")
+}
+
+/// How an item is sold, which decides how its quantity prints.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Unit {
+    /// Whole pieces: "1", never "1.00".
+    Piece,
+    /// By weight: always two decimals ("1.95"), plus `Layout.weight_label` when set.
+    Weight,
+}
+
+impl Item {
+    /// The product code to print, with `show_sku` and when there is one.
+    fn sku_line(&self, layout: &Layout) -> Option<&str> {
+        self.sku.as_deref().map(str::trim).filter(|s| layout.show_sku && !s.is_empty())
+    }
+    /// The quantity as printed: pieces whole, weights and (with `decimals` set) plain
+    /// numbers to `decimals` places (weights default to 2), `weight_label` after weights
+    /// ("1.95 كجم"); quantities that are not numbers are left alone.
+    fn qty_text(&self, decimals: Option<u8>, weight_label: Option<&str>) -> String {
+        let Ok(q) = self.qty_str.trim().parse::<f64>() else { return self.qty_str.clone() };
+        let label = weight_label.map(str::trim).filter(|l| !l.is_empty());
+        match (self.unit, decimals) {
+            (Some(Unit::Piece), _) => format!("{:.0}", q),
+            (Some(Unit::Weight), dp) => {
+                let n = format!("{:.*}", dp.unwrap_or(2) as usize, q);
+                match label {
+                    Some(l) => format!("{} {}", n, l),
+                    None => n,
+                }
+            }
+            (None, Some(dp)) => format!("{:.*}", dp as usize, q),
+            (None, None) => self.qty_str.clone(),
+        }
+    }
+    /// Line value after its own discount.
+    fn value(&self) -> f32 {
+        self.total - self.discount.unwrap_or(0.0)
+    }
+    fn has_discount(&self) -> bool {
+        self.discount.unwrap_or(0.0) > 0.0001
+    }
+    /// VAT on the line value, at its own rate or else `default_rate`.
+    fn tax(&self, default_rate: f32) -> f32 {
+        self.value() * self.tax_rate.unwrap_or(default_rate)
+    }
+}
+
+/// Items in `layout.sort` order; ties keep their input order.
+fn ordered_items<'a>(data: &'a ReceiptData, layout: &Layout) -> Vec<&'a Item> {
+    let mut items: Vec<&Item> = data.items.iter().collect();
+    match layout.sort {
+        ItemSort::None => {}
+        ItemSort::ByName => items.sort_by_cached_key(|it| arabic_sort_key(&it.name)),
+        ItemSort::ByValueDesc => items.sort_by(|a, b| b.value().total_cmp(&a.value())),
+    }
+    items
+}
+/// `name` folded for alphabetical sorting: harakat and tatweel dropped, hamza and
+/// alef variants on their base letter (أ إ آ → ا, ؤ → و, ئ ى → ي), ة with ه, and
+/// Persian forms on their Arabic letters. Code point order of the folded letters is
+/// then the dictionary order (ا ب ت ث ... ن ه و ي).
+fn arabic_sort_key(name: &str) -> String {
+    name.trim()
+        .chars()
+        .filter(|c| !matches!(c, '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{0640}'))
+        .map(|c| match c {
+            'أ' | 'إ' | 'آ' | 'ٱ' => 'ا',
+            'ؤ' => 'و',
+            'ئ' | 'ى' | 'ی' => 'ي',
+            'ة' => 'ه',
+            'ک' => 'ك',
+            'پ' => 'ب',
+            'چ' => 'ج',
+            'ژ' => 'ز',
+            'گ' => 'ك',
+            c => c.to_lowercase().next().unwrap_or(c),
+        })
+        .collect()
 }
 
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 struct ReceiptData {
-    store_name: String,
-    date_time_line: String,
+    #[serde(default)]
+    store_name: String,        // "\n" stacks a second title line (e.g. the branch)
+    #[serde(default)]
+    store: Option<StoreInfo>,  // structured header; replaces `store_name` when set
+    #[serde(default)]
+    date_time_line: String,    // printed as-is; takes precedence over `timestamp`
+    #[serde(default)]
+    timestamp: Option<i64>,    // Unix seconds, formatted with `date_time_format` when there is no `date_time_line`
+    #[serde(default)]
+    date_time_format: DateTimeFormat,
+    #[serde(default)]
     invoice_no: String,
     items: Vec<Item>,
+    #[serde(default)]
     discount: f32,
     total: f32,                // printed as-is
-    footer_address: String,
-    footer_delivery: String,
-    footer_phones: String,
+    #[serde(default)]
+    footer_lines: Vec<FooterLine>, // drawn in order under the totals
+    #[serde(default)]
+    qr: Option<String>,        // printed centered under the footer when non-empty
+    #[serde(default)]
+    qr_spec: QrSpec,           // how `qr` is printed
+    #[serde(default)]
+    logo_path: Option<String>, // image drawn above the store name
+    #[serde(default)]
+    barcode: Option<BarcodeSpec>, // invoice_no as a native barcode under the number
+    #[serde(default)]
+    open_drawer: bool,         // pulse the cash drawer after printing
+    #[serde(default)]
+    tax_rate: f32,             // VAT fraction (0.14); 0 hides the tax line
+    #[serde(default)]
+    amount_paid: Option<f32>,  // shows the payment block when set
+    #[serde(default)]
+    payment_method: Option<String>, // "نقدي", "بطاقة", ...
+    #[serde(default)]
+    currency: Option<String>,  // "ج.م", "EGP", ... appended to amounts when set
+    #[serde(default)]
+    copies: u8,                // identical copies, each with its own cut; 0 = 1
+    #[serde(default)]
+    stub: Option<StubData>,    // coupon under the footer, split off by a partial cut
+    #[serde(default)]
+    watermark: Option<String>, // bold stamp above everything else ("نسخة مكررة" on a reprint)
+}
+
+/// Seller details for a standard Egyptian invoice header: the name as the title,
+/// then one labeled line per field that is set.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct StoreInfo {
+    name: String,
+    branch: Option<String>,
+    tax_id: Option<String>,              // رقم التسجيل الضريبي
+    commercial_registry: Option<String>, // السجل التجاري
+    address: Option<String>,
+    phone: Option<String>,
+}
+impl StoreInfo {
+    /// "label: value" for each non-empty field, in print order.
+    fn lines(&self) -> Vec<String> {
+        [
+            ("الفرع", &self.branch),
+            ("رقم التسجيل الضريبي", &self.tax_id),
+            ("السجل التجاري", &self.commercial_registry),
+            ("العنوان", &self.address),
+            ("تليفون", &self.phone),
+        ]
+        .into_iter()
+        .filter_map(|(label, v)| v.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(|v| format!("{}: {}", label, v)))
+        .collect()
+    }
+}
+
+/// How `ReceiptData.qr` is printed.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct QrSpec {
+    native: bool,    // `GS ( k` (printers with a QR engine, also in the text modes) instead of a bitmap; not in a side-by-side header
+    ec: QrEc,        // error correction: higher levels scan off worn thermal paper but hold less data
+    model: u8,       // native only: 1 or 2
+    module_size: u8, // native only: dots per module, 1-16
+}
+impl Default for QrSpec {
+    fn default() -> Self {
+        Self { native: false, ec: QrEc::M, model: 2, module_size: 6 }
+    }
+}
+
+/// QR error-correction level: roughly 7, 15, 25 or 30 % of the code can be lost.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum QrEc {
+    L,
+    M,
+    Q,
+    H,
+}
+impl QrEc {
+    fn level(self) -> EcLevel {
+        match self {
+            QrEc::L => EcLevel::L,
+            QrEc::M => EcLevel::M,
+            QrEc::Q => EcLevel::Q,
+            QrEc::H => EcLevel::H,
+        }
+    }
+}
+
+/// A detachable stub (promo coupon) printed after the receipt's own content.
+#[derive(Clone, Deserialize, Serialize)]
+struct StubData {
+    #[serde(default)]
+    lines: Vec<String>,  // centered message ("خصم ١٠٪ على زيارتك القادمة")
+    #[serde(default)]
+    qr: Option<String>,  // under the message; bitmap modes only
+}
+
+impl ReceiptData {
+    /// The title text: `store.name` when structured store info is given, else `store_name`.
+    fn title(&self) -> &str {
+        match &self.store {
+            Some(store) => &store.name,
+            None => &self.store_name,
+        }
+    }
+    /// Sum of item values (after per-item discounts) before the receipt-level discount and tax.
+    fn subtotal(&self) -> f32 {
+        self.items.iter().map(Item::value).sum()
+    }
+    /// The invoice number, after `layout.invoice_label` when one is set.
+    fn invoice_line(&self, layout: &Layout) -> Cow<'_, str> {
+        match layout.invoice_label.as_deref().map(str::trim).filter(|l| !l.is_empty()) {
+            Some(label) if !self.invoice_no.is_empty() => Cow::Owned(format!("{}: {}", label, self.invoice_no)),
+            _ => Cow::Borrowed(&self.invoice_no),
+        }
+    }
+    /// The reprint stamp, when there is one to print.
+    fn watermark(&self) -> Option<&str> {
+        self.watermark.as_deref().map(str::trim).filter(|m| !m.is_empty())
+    }
+    /// The header date line: `date_time_line` as given, else the formatted `timestamp`.
+    fn date_time(&self) -> Cow<'_, str> {
+        if !self.date_time_line.is_empty() {
+            return Cow::Borrowed(&self.date_time_line);
+        }
+        self.timestamp.and_then(|t| self.date_time_format.format(t)).map_or(Cow::Borrowed(""), Cow::Owned)
+    }
+    /// Sum of the per-line taxes (each item at its own rate, or the receipt's).
+    fn tax_amount(&self) -> f32 {
+        self.items.iter().map(|i| i.tax(self.tax_rate)).sum()
+    }
+    fn has_tax(&self) -> bool {
+        self.tax_rate > 0.0 || self.items.iter().any(|i| i.tax_rate.unwrap_or(0.0) > 0.0)
+    }
+    /// With VAT or per-item discounts the total is computed (subtotal − discount + tax),
+    /// so both discount levels stack; otherwise `total` is printed exactly as provided.
+    fn grand_total(&self) -> f32 {
+        if self.items.is_empty() {
+            0.0 // nothing sold, whatever `total` was sent
+        } else if self.has_tax() || self.items.iter().any(Item::has_discount) {
+            self.subtotal() - self.discount + self.tax_amount()
+        } else {
+            self.total
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct FooterLine {
+    text: String,
+    #[serde(default)]
+    size: Option<f32>, // px; None = `fonts.footer`, or `fonts.footer_phones` for Latin/number-only lines
+    #[serde(default)]
+    align: Align,
+    #[serde(default)]
+    red: bool,         // second color on two-color paper (a "مدفوع" stamp, ...)
+}
+
+/// How `timestamp` is printed; the default gives "٤ نوفمبر - ٤:٠٩ صباحا".
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+struct DateTimeFormat {
+    hour12: bool,              // "4:09 صباحا" rather than "04:09"
+    arabic_months: bool,       // "4 نوفمبر" rather than "4/11"
+    show_year: bool,
+    digits: NumberLocale,
+    utc_offset_minutes: Option<i32>, // None = this machine's time zone
+}
+
+impl Default for DateTimeFormat {
+    fn default() -> Self {
+        Self { hour12: true, arabic_months: true, show_year: false, digits: NumberLocale::ArabicIndic, utc_offset_minutes: None }
+    }
+}
+
+const ARABIC_MONTHS: [&str; 12] = [
+    "يناير", "فبراير", "مارس", "أبريل", "مايو", "يونيو",
+    "يوليو", "أغسطس", "سبتمبر", "أكتوبر", "نوفمبر", "ديسمبر",
+];
+
+impl DateTimeFormat {
+    /// None for timestamps or offsets chrono can't represent.
+    fn format(&self, timestamp: i64) -> Option<String> {
+        let utc = chrono::DateTime::from_timestamp(timestamp, 0)?;
+        let t = match self.utc_offset_minutes {
+            Some(m) => utc.with_timezone(&chrono::FixedOffset::east_opt(m * 60)?).naive_local(),
+            None => utc.with_timezone(&chrono::Local).naive_local(),
+        };
+        let mut date = if self.arabic_months {
+            format!("{} {}", t.day(), ARABIC_MONTHS[t.month0() as usize])
+        } else {
+            format!("{}/{}", t.day(), t.month())
+        };
+        if self.show_year {
+            date += &if self.arabic_months { format!(" {}", t.year()) } else { format!("/{}", t.year()) };
+        }
+        let time = if self.hour12 {
+            let (pm, h) = t.hour12();
+            format!("{}:{:02} {}", h, t.minute(), if pm { "مساء" } else { "صباحا" })
+        } else {
+            format!("{:02}:{:02}", t.hour(), t.minute())
+        };
+        Some(localize_digits(&format!("{} - {}", date, time), self.digits))
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Align {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Symbology {
+    Code128,
+    Ean13,
+    Upca,
+}
+
+/// How a barcode reaches the paper.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BarcodeRenderMode {
+    #[default]
+    Native, // `GS k`
+    Bitmap, // bars drawn into the receipt bitmap, for printers without `GS k` (bitmap modes only)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct BarcodeSpec {
+    symbology: Symbology,
+    #[serde(default = "default_barcode_height")]
+    height: u8,       // dots
+    #[serde(default = "default_barcode_module")]
+    module_width: u8, // dots per narrow bar (2..6 native, 1..6 bitmap)
+    #[serde(default)]
+    render: BarcodeRenderMode,
 }
+fn default_barcode_height() -> u8 { 80 }
+fn default_barcode_module() -> u8 { 2 }
 
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 struct Layout {
     paper_width_px: u32,
-    threshold: u8,
-    margin_h: i32,
-    margin_top: i32,
+    #[serde(alias = "text_threshold")]
+    threshold: u8,             // gray level (0-255) at or below which a pixel prints, applied when packing: text, rules, QR
+    margin_h: i32,             // deprecated: both sides, for whichever of margin_left/right is unset
+    margin_left: Option<i32>,  // px kept blank on each side, e.g. to clear a printer that clips an edge
+    margin_right: Option<i32>,
+    margin_top: Option<i32>,   // None = crop exactly to the title's first ink row
     margin_bottom: i32,
     row_gap: i32,
     fonts: Fonts,
-    // RTL columns as percentages of inner width: [name, qty, price, total]
+    // RTL columns as fractions of inner width, by position from the right
     cols: [f32; 4],
+    col_order: [ColumnKind; 4], // which column sits at each position, rightmost first
+    header_align: [Align; 4],  // column header within its column, by position; "center" sits it over right-aligned numbers
+    invoice_label: Option<String>, // "رقم الفاتورة" printed before the invoice number ("رقم الفاتورة: 123456")
+    invoice_align: Align,
+    tax_col: f32,              // `ItemizedTax` only: width of the leftmost tax column, taken off the other four
+    show_item_index: bool,     // bitmap only: number the items (in `number_locale` digits) in a "#" column at the far right
+    index_col: f32,            // width of that column, taken off the other four
+    codepage_id: u8,           // `CodePage864` only: `ESC t n` for PC864 (37 on Epson-style firmware; vendors differ)
+    normalize_cols: bool,      // rescale `cols` to sum to 1.0 instead of rejecting them
+    qr_module_size: u32,       // px per QR module
+    font_path: Option<String>, // TTF to use instead of the bundled Noto Sans Arabic
+    latin_font_path: Option<String>, // TTF for LTR runs (digits, phones, totals)
+    bold_font_path: Option<String>,  // bold TTF for the total row; None = faux bold
+    raster_mode: RasterMode,
+    cut_mode: CutMode,
+    feed_before_cut: u8,       // line feeds (or dots, see feed_unit) between the footer and the cut
+    feed_unit: FeedUnit,
+    band_feed: Option<u8>,     // dots between `ESC *` bands via `ESC J n` (24 = exact); 0 = none (auto-advancing printers); None = LF
+    line_spacing_dots: Option<u8>, // `ESC 3 n` while the bands go out (24 = one band per LF); None = printer default
+    drawer_pin: u8,            // 0 = connector pin 2, 1 = pin 5
+    drawer_on_ms: u16,         // pulse length
+    drawer_off_ms: u16,        // pause after the pulse
+    density: Option<u8>,       // print darkness 0-100 % (50 = printer default); None leaves the printer's setting alone
+    density_command: DensityCommand,
+    separator: SeparatorStyle,
+    separator_thickness: u32,  // px per line
+    row_separator: Option<SeparatorStyle>, // rule between item rows; None = plain spacing
+    row_separator_every: u8,   // rule after every N items (0 or 1 = each)
+    render_mode: RenderMode,
+    reshape: ReshapeConfig,
+    char_spacing: u8,          // extra px between Arabic letters that don't join (joined ones stay connected)
+    number_locale: NumberLocale,
+    thousands_separator: bool,
+    currency_position: CurrencyPosition,
+    currency_on_prices: bool,  // also on item price/total cells, not only the totals
+    #[serde(alias = "image_threshold")]
+    logo_threshold: Option<u8>, // logo and printed images only, applied before packing so `threshold` leaves them alone; None = `threshold`
+    dither: bool,              // Floyd–Steinberg the logo instead of thresholding it
+    copy_captions: Vec<String>, // caption above copy N ("نسخة العميل", "نسخة التاجر"); none when empty
+    beep_on_print: bool,       // sound the buzzer after the job (printers with a buzzer)
+    paper_check: PaperCheck,   // what a job does when the paper runs out mid-print
+    supersample: u8,           // 1-3: render at N× and downsample, for smoother small text
+    zebra: bool,               // light dot shading behind every other item row
+    name_overflow: Overflow,
+    show_sku: bool,            // item `sku` under the name, before its notes
+    empty_items: EmptyItems,
+    header_layout: HeaderLayout,
+    rotate_180: bool,          // raster printed upside down, for printers mounted feeding toward the operator
+    show_total_in_words: bool, // "فقط ... لا غير" under the total
+    missing_glyph: char,       // drawn for characters no face has (emoji, ...); '?' when the face lacks it too
+    pdf_width_mm: f32,         // `receipt_to_pdf` page width, the bitmap scaled to fill it
+    weight_label: Option<String>, // after `unit: weight` quantities ("كجم", "kg"); None = the number only
+    qty_decimals: Option<u8>,  // places for numeric quantities; None = as given (weights: 2)
+    price_decimals: u8,        // places for unit prices
+    total_decimals: u8,        // places for line totals and the totals block
+    sort: ItemSort,            // item order on the receipt
+    red_total: bool,           // total row in the second color on two-color paper
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CurrencyPosition {
+    #[default]
+    After,  // read after the number: to its left on the RTL receipt
+    Before, // read before the number: to its right
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ColumnKind {
+    Name,
+    Qty,
+    Price,
+    Total,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum NumberLocale {
+    #[default]
+    Western,     // 1,234.50
+    ArabicIndic, // ١٬٢٣٤٫٥٠
+}
+
+/// Options forwarded to `ar_reshaper`. Persian and Urdu letters (پ چ ژ گ ی ے ...)
+/// are covered by the Arabic tables; Kurdish has its own.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReshapeConfig {
+    language: ReshapeLanguage,
+    delete_harakat: bool,
+    support_ligatures: bool,
+}
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ReshapeLanguage {
+    #[default]
+    Arabic,
+    ArabicV2,
+    Kurdish,
+}
+impl Default for ReshapeConfig {
+    fn default() -> Self {
+        Self { language: ReshapeLanguage::Arabic, delete_harakat: true, support_ligatures: true }
+    }
+}
+impl ReshapeConfig {
+    fn reshaper(&self) -> ArabicReshaper {
+        let language = match self.language {
+            ReshapeLanguage::Arabic => Language::Arabic,
+            ReshapeLanguage::ArabicV2 => Language::ArabicV2,
+            ReshapeLanguage::Kurdish => Language::Kurdish,
+        };
+        ArabicReshaper::new(ReshaperConfig {
+            language,
+            delete_harakat: self.delete_harakat,
+            support_ligatures: self.support_ligatures,
+            ..ReshaperConfig::default()
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RenderMode {
+    /// Everything drawn into one bitmap (needed for Arabic).
+    #[default]
+    Bitmap,
+    /// Printer-native text for pure-ASCII receipts; falls back to `Bitmap` otherwise.
+    Text,
+    /// Kitchen ticket: bitmap with names and quantities only, no prices, totals or footer.
+    Kitchen,
+    /// B2B invoice: bitmap with a fifth column holding each line's tax, and a tax total.
+    ItemizedTax,
+    /// Printer-native text in the built-in Arabic code page (CP864), for printers without graphics.
+    #[serde(rename = "codepage864")]
+    CodePage864,
+}
+
+/// Where the logo and QR go.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum HeaderLayout {
+    /// Logo centered above the store name, QR under the footer.
+    #[default]
+    Stacked,
+    /// One band above the store name: logo on the right, QR (e.g. a membership code) on the left.
+    SideBySide,
+}
+
+/// Order of the item rows.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ItemSort {
+    /// As sent (entry order).
+    #[default]
+    None,
+    /// Alphabetical, Arabic letters in dictionary order.
+    ByName,
+    /// Highest line value first.
+    ByValueDesc,
+}
+
+/// What a receipt without items prints.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EmptyItems {
+    /// A "لا توجد أصناف" row where the items go, and a zero total.
+    #[default]
+    Message,
+    /// Nothing: rendering fails with "receipt has no items".
+    Reject,
+}
+const EMPTY_ITEMS_MESSAGE: &str = "لا توجد أصناف";
+
+/// What happens to an item name wider than its column.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Overflow {
+    /// Continue on more lines under the name.
+    #[default]
+    Wrap,
+    /// One line, cut short with "…" at its end (the left edge of an RTL name).
+    Ellipsis,
+    /// One line in a smaller font (down to half size, then cut like `Ellipsis`).
+    Shrink,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SeparatorStyle {
+    #[default]
+    Dotted,
+    Dashed,
+    Solid,
+    Double,
+    /// A row of this glyph drawn as text: `{"chars": "═"}`
+    Chars(char),
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CutMode {
+    #[default]
+    Full,
+    /// `GS V 1`, for cutters that only do partial cuts.
+    Partial,
+    /// No cut command at all (printers without a cutter).
+    None,
+    /// Tear bar instead of a cutter: feed the last line past it (`ESC J`), no cut command.
+    /// `{"tear_off": {"feed_dots": 120}}`
+    TearOff { feed_dots: u16 },
+}
+
+/// Unit of `feed_before_cut`.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FeedUnit {
+    #[default]
+    Lines, // LF × n
+    Dots,  // ESC J n
+}
+
+/// How the bitmap is sent to the printer.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RasterMode {
+    /// `ESC *` 24-dot double-density bands (works everywhere).
+    #[default]
+    EscStar24,
+    /// One `GS v 0` raster image; avoids seams between bands on newer printers.
+    GsV0,
+    /// Uncompressed `GS ( L` graphics in strips of at most `GRAPHICS_STRIP_ROWS` rows.
+    Graphics,
+}
+
+/// Paper-out handling while a job is sent. Polls `DLE EOT` between pieces, so it needs
+/// a link that reads back; a printer that never answers is not asked again that job.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PaperCheck {
+    /// No polling: the job stalls silently until paper is loaded.
+    #[default]
+    Off,
+    /// Stop the job with an "out of paper" error.
+    Abort,
+    /// Hold the rest of the job until paper is loaded, for at most `timeout_ms`
+    /// (up to `MAX_PAPER_WAIT_MS`; other jobs for the printer queue behind it).
+    Wait { timeout_ms: u32 },
+}
+
+/// Which vendor command sets `density`.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DensityCommand {
+    /// `GS ( K 2 0 49 m` (Epson TM and clones): 13 steps, -6..=+6 around the default.
+    #[default]
+    Epson,
+    /// `DC2 # n` (most Chinese 58/80 mm mechanisms): 32 steps of heating.
+    Dc2,
 }
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 struct Fonts {
     title: f32,
+    title_min: f32, // a title line too wide for the paper shrinks, down to this
     header_dt: f32,
     header_no: f32,
     header_cols: f32,
@@ -81,6 +873,24 @@ struct Fonts {
     total_value: f32,
     footer: f32,
     footer_phones: f32,
+    note: f32,
+}
+impl Default for Fonts {
+    fn default() -> Self {
+        Self {
+            title: 90.0,
+            title_min: 48.0,
+            header_dt: 45.0,
+            header_no: 46.0,
+            header_cols: 42.0,
+            item: 44.0,
+            total_label: 48.0,
+            total_value: 66.0,
+            footer: 45.0,
+            footer_phones: 56.0,
+            note: 34.0,
+        }
+    }
 }
 impl Default for Layout {
     fn default() -> Self {
@@ -88,22 +898,252 @@ impl Default for Layout {
             paper_width_px: 576,
             threshold: 150,
             margin_h: 0,
-            margin_top: -28,
+            margin_left: None,
+            margin_right: None,
+            margin_top: None,
             margin_bottom: 0,
             row_gap: 32,
-            fonts: Fonts {
-                title: 90.0,
-                header_dt: 45.0,
-                header_no: 46.0,
-                header_cols: 42.0,
-                item: 44.0,
-                total_label: 48.0,
-                total_value: 66.0,
-                footer: 45.0,
-                footer_phones: 56.0,
-            },
+            fonts: Fonts::default(),
             // Name, Qty, Price, Total (must sum to 1.00)
             cols: [0.60, 0.12, 0.12, 0.16],
+            normalize_cols: false,
+            col_order: [ColumnKind::Name, ColumnKind::Qty, ColumnKind::Price, ColumnKind::Total],
+            header_align: [Align::Right; 4],
+            invoice_label: None,
+            invoice_align: Align::Center,
+            tax_col: 0.14,
+            show_item_index: false,
+            index_col: 0.06,
+            codepage_id: 37,
+            qr_module_size: 6,
+            font_path: None,
+            latin_font_path: None,
+            bold_font_path: None,
+            raster_mode: RasterMode::EscStar24,
+            cut_mode: CutMode::Full,
+            feed_before_cut: 1,
+            feed_unit: FeedUnit::Lines,
+            band_feed: None,
+            line_spacing_dots: None,
+            drawer_pin: 0,
+            drawer_on_ms: 100,
+            drawer_off_ms: 500,
+            density: None,
+            density_command: DensityCommand::Epson,
+            separator: SeparatorStyle::Dotted,
+            separator_thickness: 1,
+            row_separator: None,
+            row_separator_every: 1,
+            render_mode: RenderMode::Bitmap,
+            reshape: ReshapeConfig::default(),
+            char_spacing: 0,
+            number_locale: NumberLocale::Western,
+            thousands_separator: false,
+            currency_position: CurrencyPosition::After,
+            currency_on_prices: false,
+            logo_threshold: None,
+            dither: false,
+            copy_captions: Vec::new(),
+            beep_on_print: false,
+            paper_check: PaperCheck::Off,
+            supersample: 1,
+            zebra: false,
+            name_overflow: Overflow::Wrap,
+            show_sku: false,
+            empty_items: EmptyItems::Message,
+            header_layout: HeaderLayout::Stacked,
+            rotate_180: false,
+            show_total_in_words: false,
+            missing_glyph: '\u{25A1}', // □
+            pdf_width_mm: 80.0,
+            weight_label: None,
+            qty_decimals: None,
+            price_decimals: 2,
+            total_decimals: 2,
+            sort: ItemSort::None,
+            red_total: false,
+        }
+    }
+}
+
+impl Fonts {
+    fn scaled(&self, f: f32) -> Fonts {
+        Fonts {
+            title: self.title * f,
+            title_min: self.title_min * f,
+            header_dt: self.header_dt * f,
+            header_no: self.header_no * f,
+            header_cols: self.header_cols * f,
+            item: self.item * f,
+            total_label: self.total_label * f,
+            total_value: self.total_value * f,
+            footer: self.footer * f,
+            footer_phones: self.footer_phones * f,
+            note: self.note * f,
+        }
+    }
+}
+impl Layout {
+    /// Defaults (tuned for 576 px / 80 mm) scaled to another paper width, e.g. 384 px for 58 mm.
+    fn for_width(px: u32) -> Self {
+        let base = Self::default();
+        let mut layout = base.scaled(px as f32 / base.paper_width_px as f32);
+        layout.paper_width_px = px;
+        layout
+    }
+    /// Every pixel dimension multiplied by `f`; column fractions and thresholds are unchanged.
+    fn scaled(&self, f: f32) -> Self {
+        let px = |v: i32| (v as f32 * f).round() as i32;
+        Self {
+            paper_width_px: (self.paper_width_px as f32 * f).round() as u32,
+            margin_h: px(self.margin_h),
+            margin_left: self.margin_left.map(px),
+            margin_right: self.margin_right.map(px),
+            margin_top: self.margin_top.map(px),
+            margin_bottom: px(self.margin_bottom),
+            row_gap: px(self.row_gap),
+            fonts: self.fonts.scaled(f),
+            qr_module_size: ((self.qr_module_size as f32 * f).round() as u32).max(1),
+            separator_thickness: ((self.separator_thickness as f32 * f).round() as u32).max(1),
+            char_spacing: (self.char_spacing as f32 * f).round() as u8,
+            ..self.clone()
+        }
+    }
+    /// (left, right) blank margins in px, falling back to `margin_h` per side.
+    fn margins(&self) -> (i32, i32) {
+        (self.margin_left.unwrap_or(self.margin_h), self.margin_right.unwrap_or(self.margin_h))
+    }
+    /// Kitchen-ticket variant of this layout: `RenderMode::Kitchen` with larger item text.
+    fn kitchen(&self) -> Self {
+        Self {
+            render_mode: RenderMode::Kitchen,
+            row_gap: (self.row_gap as f32 * 1.4).round() as i32,
+            fonts: Fonts { item: self.fonts.item * 1.4, header_cols: self.fonts.header_cols * 1.2, ..self.fonts.clone() },
+            ..self.clone()
+        }
+    }
+    /// Rejects column fractions that would overlap or leave gaps: each must be
+    /// positive and together they must sum to 1.0 (± 0.01).
+    fn validate(&self) -> Result<(), String> {
+        if let Some(i) = self.cols.iter().position(|c| !c.is_finite() || *c <= 0.0) {
+            return Err(format!("layout.cols[{}] must be positive, got {}", i, self.cols[i]));
+        }
+        let sum: f32 = self.cols.iter().sum();
+        if (sum - 1.0).abs() > 0.01 {
+            return Err(format!("layout.cols must sum to 1.0, got {:.3} ({:?})", sum, self.cols));
+        }
+        for (name, dp) in [("qty_decimals", self.qty_decimals.unwrap_or(0)), ("price_decimals", self.price_decimals), ("total_decimals", self.total_decimals)] {
+            if dp > 4 {
+                return Err(format!("layout.{} must be at most 4, got {}", name, dp));
+            }
+        }
+        if !(self.pdf_width_mm.is_finite() && self.pdf_width_mm > 0.0) {
+            return Err(format!("layout.pdf_width_mm must be positive, got {}", self.pdf_width_mm));
+        }
+        if !(self.tax_col > 0.0 && self.tax_col < 0.5) {
+            return Err(format!("layout.tax_col must be between 0 and 0.5, got {}", self.tax_col));
+        }
+        if !(self.index_col > 0.0 && self.index_col < 0.5) {
+            return Err(format!("layout.index_col must be between 0 and 0.5, got {}", self.index_col));
+        }
+        if let PaperCheck::Wait { timeout_ms } = self.paper_check {
+            if timeout_ms > MAX_PAPER_WAIT_MS {
+                return Err(format!("layout.paper_check.timeout_ms must be at most {}, got {}", MAX_PAPER_WAIT_MS, timeout_ms));
+            }
+        }
+        for kind in [ColumnKind::Name, ColumnKind::Qty, ColumnKind::Price, ColumnKind::Total] {
+            if !self.col_order.contains(&kind) {
+                return Err("layout.col_order must list name, qty, price and total once each".into());
+            }
+        }
+        Ok(())
+    }
+    /// Parses, normalizes (if asked) and validates a layout, reporting problems
+    /// with the path of the offending field so a UI can point at it.
+    fn from_json_detailed(json: &str) -> Result<Self, LayoutError> {
+        let de = &mut serde_json::Deserializer::from_str(json);
+        let layout: Layout = serde_path_to_error::deserialize(de).map_err(LayoutError::from_serde)?;
+        layout.checked().map_err(|message| LayoutError::Invalid { path: String::new(), message })
+    }
+    /// Normalizes the columns (if asked) and validates a deserialized layout.
+    fn checked(mut self) -> Result<Self, String> {
+        if self.normalize_cols { self.normalize_cols(); }
+        self.validate()?;
+        Ok(self)
+    }
+    /// Position (0 = rightmost) of a column in `col_order`.
+    fn col_pos(&self, kind: ColumnKind) -> usize {
+        self.col_order.iter().position(|c| *c == kind).unwrap_or(0)
+    }
+    /// Rescales positive column fractions so they sum to exactly 1.0.
+    fn normalize_cols(&mut self) {
+        let sum: f32 = self.cols.iter().sum();
+        if sum > 0.0 && self.cols.iter().all(|c| c.is_finite() && *c > 0.0) {
+            self.cols.iter_mut().for_each(|c| *c /= sum);
+        }
+    }
+}
+
+/// Why a layout JSON was rejected; `path` is like "fonts.title" or "cols[2]".
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum LayoutError {
+    Syntax { line: usize, column: usize, message: String },
+    UnknownField { path: String, field: String, expected: Vec<String> },
+    UnknownVariant { path: String, value: String, expected: Vec<String> },
+    WrongType { path: String, expected: String, found: String },
+    Invalid { path: String, message: String },
+}
+
+impl LayoutError {
+    fn from_serde(e: serde_path_to_error::Error<serde_json::Error>) -> Self {
+        let path = e.path().to_string();
+        let path = if path == "." { String::new() } else { path }; // "." is the root
+        let inner = e.inner();
+        let suffix = format!(" at line {} column {}", inner.line(), inner.column());
+        let full = inner.to_string();
+        let msg = full.strip_suffix(&suffix).unwrap_or(&full).to_string();
+        if !inner.is_data() {
+            return LayoutError::Syntax { line: inner.line(), column: inner.column(), message: msg };
+        }
+        // serde quotes names in backticks: "unknown field `foo`, expected one of `a`, `b`"
+        let quoted: Vec<String> = msg.split('`').skip(1).step_by(2).map(str::to_string).collect();
+        let (first, rest) = match quoted.split_first() {
+            Some((f, r)) => (f.clone(), r.to_vec()),
+            None => (String::new(), Vec::new()),
+        };
+        if msg.starts_with("unknown field") {
+            // the path may or may not already include the unknown key itself
+            let path = path.strip_suffix(first.as_str()).map(|p| p.trim_end_matches('.').to_string()).unwrap_or(path);
+            LayoutError::UnknownField { path, field: first, expected: rest }
+        } else if msg.starts_with("unknown variant") {
+            LayoutError::UnknownVariant { path, value: first, expected: rest }
+        } else if let Some((found, expected)) = msg.strip_prefix("invalid type: ")
+            .or_else(|| msg.strip_prefix("invalid length "))
+            .and_then(|m| m.split_once(", expected "))
+        {
+            LayoutError::WrongType { path, expected: expected.to_string(), found: found.to_string() }
+        } else {
+            LayoutError::Invalid { path, message: msg }
+        }
+    }
+}
+
+impl std::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let at = |path: &str| if path.is_empty() { "layout".to_string() } else { format!("layout.{}", path) };
+        match self {
+            LayoutError::Syntax { line, column, message } => write!(f, "{} (line {}, column {})", message, line, column),
+            LayoutError::UnknownField { path, field, expected } if expected.is_empty() =>
+                write!(f, "{} has no field '{}'", at(path), field),
+            LayoutError::UnknownField { path, field, expected } =>
+                write!(f, "{} has no field '{}' (known fields: {})", at(path), field, expected.join(", ")),
+            LayoutError::UnknownVariant { path, value, expected } =>
+                write!(f, "{}: '{}' is not one of {}", at(path), value, expected.join(", ")),
+            LayoutError::WrongType { path, expected, found } =>
+                write!(f, "{}: expected {}, got {}", at(path), expected, found),
+            LayoutError::Invalid { path, message } if path.is_empty() => write!(f, "{}", message),
+            LayoutError::Invalid { path, message } => write!(f, "{}: {}", at(path), message),
         }
     }
 }
@@ -117,7 +1157,7 @@ enum Qty {
     Num(f64),
 }
 impl Qty {
-    fn to_display(self) -> String {
+    fn into_display(self) -> String {
         match self {
             Qty::Str(s) => s,
             Qty::Num(n) => n.to_string(), // 1 -> "1", 1.01 -> "1.01"
@@ -125,12 +1165,28 @@ impl Qty {
     }
 }
 
+fn de_qty<'de, D: serde::Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    Qty::deserialize(d).map(Qty::into_display)
+}
+
 #[derive(Deserialize, Clone)]
 struct FrontendItem {
     name: String,
     qty: Qty,       // string or number, preserved
     price: f32,
     total: f32,     // provided by frontend, printed as-is
+    #[serde(default)]
+    discount: Option<f32>,
+    #[serde(default, alias = "taxRate")]
+    tax_rate: Option<f32>,
+    #[serde(default)]
+    notes: Vec<String>,
+    #[serde(default)]
+    unit: Option<Unit>,
+    #[serde(default)]
+    sku: Option<String>,
+    #[serde(default, alias = "forceLtr")]
+    force_ltr: bool,
 }
 
 #[derive(Deserialize, Clone)]
@@ -142,323 +1198,3973 @@ struct FrontendFooter {
     phones: Option<String>,
 }
 
+/// The old fixed footer: address and last line always (centered), phones when present.
+impl From<FrontendFooter> for Vec<FooterLine> {
+    fn from(f: FrontendFooter) -> Self {
+        let line = |text: String| FooterLine { text, size: None, align: Align::Center, red: false };
+        let mut lines = vec![line(f.address), line(f.last_line)];
+        lines.extend(f.phones.filter(|p| !p.is_empty()).map(line));
+        lines
+    }
+}
+
 // ---------------- Arabic shaping + drawing ----------------
 
-fn shape(s: &str) -> String { reshape_line(s) }
+const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/NotoSansArabic-Regular.ttf");
+
+/// Font bytes read from `path`, or the bundled Noto Sans Arabic when `None`.
+fn load_font_bytes(path: Option<&str>) -> Result<Cow<'static, [u8]>, String> {
+    match path {
+        Some(p) => std::fs::read(p).map(Cow::Owned).map_err(|e| format!("font '{}': {}", p, e)),
+        None => Ok(Cow::Borrowed(DEFAULT_FONT)),
+    }
+}
+
 fn draw_crisp(img: &mut RgbImage, s: &str, x: i32, y: i32, scale: PxScale, font: &FontRef) {
     draw_text_mut(img, Rgb([0,0,0]), x, y, scale, font, s);
 }
-fn is_ltr_char(c: char) -> bool {
-    c.is_ascii()
-        || ('\u{0660}'..='\u{0669}').contains(&c)
-        || ('\u{06F0}'..='\u{06F9}').contains(&c)
-        || ":./-–—,".contains(c)
+/// Strong right-to-left character (Hebrew/Arabic letters, incl. presentation forms).
+fn is_rtl_char(c: char) -> bool {
+    matches!(bidi_class(c), BidiClass::R | BidiClass::AL)
+}
+/// Anything the reshaper acts on: the Arabic blocks and their presentation forms.
+fn is_arabic_char(c: char) -> bool {
+    matches!(c, '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' | '\u{08A0}'..='\u{08FF}'
+                | '\u{FB50}'..='\u{FDFF}' | '\u{FE70}'..='\u{FEFF}')
+}
+/// Harakat and other marks drawn over or under a letter.
+fn is_arabic_mark(c: char) -> bool {
+    matches!(c, '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{FE70}'..='\u{FE7F}')
+}
+/// Initial forms of the dual-joining letters in Arabic Presentation Forms-B; each
+/// letter's medial form follows its initial form.
+const INITIAL_FORMS: [u32; 23] = [
+    0xFE8B, 0xFE91, 0xFE97, 0xFE9B, 0xFE9F, 0xFEA3, 0xFEA7, 0xFEB3, 0xFEB7, 0xFEBB, 0xFEBF, 0xFEC3,
+    0xFEC7, 0xFECB, 0xFECF, 0xFED3, 0xFED7, 0xFEDB, 0xFEDF, 0xFEE3, 0xFEE7, 0xFEEB, 0xFEF3,
+];
+/// Whether a shaped letter connects to the letter after it (on its left): initial
+/// and medial forms, and tatweel.
+fn joins_left(c: char) -> bool {
+    c == '\u{0640}' || INITIAL_FORMS.iter().any(|&f| (f..=f + 1).contains(&(c as u32)))
+}
+/// Zero-width marks that steer bidi ordering and are never drawn.
+fn is_bidi_mark(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{061C}')
+}
+/// Mirrored glyph for paired punctuation that ends up inside an RTL run.
+fn mirror_char(c: char) -> char {
+    match c {
+        '(' => ')', ')' => '(',
+        '[' => ']', ']' => '[',
+        '{' => '}', '}' => '{',
+        '<' => '>', '>' => '<',
+        '«' => '»', '»' => '«',
+        _ => c,
+    }
 }
-fn draw_mixed_rtl_right(img: &mut RgbImage, font: &FontRef, scale: PxScale, logical: &str, x_right: i32, y: i32) {
-    let shaped = shape(logical);
-    let mut runs: Vec<(bool, String, i32)> = Vec::new();
-    let mut cur = String::new();
-    let mut cur_is_ltr = None::<bool>;
 
-    for ch in shaped.chars() {
-        let ltr = is_ltr_char(ch);
-        match cur_is_ltr {
-            None => { cur_is_ltr = Some(ltr); cur.push(ch); }
-            Some(kind) if kind == ltr => cur.push(ch),
-            Some(_) => {
-                let w = if cur_is_ltr.unwrap() { text_size(scale, font, &cur).0 as i32 }
-                        else { cur.chars().map(|c| text_size(scale, font, &c.to_string()).0 as i32).sum() };
-                runs.push((cur_is_ltr.unwrap(), cur.clone(), w));
-                cur.clear(); cur_is_ltr = Some(ltr); cur.push(ch);
-            }
+/// Faces and shaping used to draw a receipt: Arabic for RTL runs, Latin for LTR runs.
+/// Both are the same face unless `Layout.latin_font_path` is set.
+struct Typesetter<'a> {
+    arabic: FontRef<'a>,
+    latin: FontRef<'a>,
+    reshaper: ArabicReshaper,
+    bold: Option<Box<Typesetter<'a>>>, // real bold face for both scripts, when configured
+    char_spacing: i32,                 // extra px between Arabic letters that don't join
+}
+impl<'a> Typesetter<'a> {
+    /// Contextual Arabic forms (presentation forms), still in logical order.
+    /// Text without Arabic (phone numbers, codes, Latin names) is returned as is.
+    fn shape(&self, s: &str) -> String {
+        if !s.chars().any(is_arabic_char) {
+            return s.to_string();
         }
+        self.reshaper.reshape(s)
     }
-    if !cur.is_empty() {
-        let w = if cur_is_ltr.unwrap() { text_size(scale, font, &cur).0 as i32 }
-                else { cur.chars().map(|c| text_size(scale, font, &c.to_string()).0 as i32).sum() };
-        runs.push((cur_is_ltr.unwrap(), cur.clone(), w));
+    /// Face for an LTR run: Latin when it covers every character
+    /// (Arabic-Indic digits usually are not), Arabic otherwise.
+    fn ltr_font(&self, s: &str) -> &FontRef<'a> {
+        if s.chars().all(|c| c == ' ' || self.latin.glyph_id(c).0 != 0) { &self.latin } else { &self.arabic }
     }
-
-    let total_w: i32 = runs.iter().map(|r| r.2).sum();
-    let mut right = x_right;
-
-    for (is_ltr, seg, w) in runs.into_iter() {
-        let start_x = right - w;
-        if is_ltr {
-            draw_ltr_right(img, font, scale, &seg, right, y);
-        } else {
-            let chars: Vec<char> = seg.chars().collect();
-            let cw: Vec<i32> = chars.iter().map(|c| text_size(scale, font, &c.to_string()).0 as i32).collect();
-            let mut x = start_x;
-            for i in (0..chars.len()).rev() {
-                draw_crisp(img, &chars[i].to_string(), x, y, scale, font);
-                x += cw[i];
-            }
-        }
-        right -= w;
-        if right < x_right - total_w { break; }
+    fn ltr_width(&self, scale: PxScale, s: &str) -> i32 {
+        text_size(scale, self.ltr_font(s), s).0 as i32
+    }
+    fn rtl_char_width(&self, scale: PxScale, c: char) -> i32 {
+        text_size(scale, &self.arabic, &c.to_string()).0 as i32
+    }
+    /// How far each char of a visual RTL run moves the pen: its width, plus
+    /// `char_spacing` unless the next letter to its right joins onto it (a gap there
+    /// would break the connection). Marks never get spacing.
+    fn rtl_advances(&self, scale: PxScale, vis: &str) -> Vec<i32> {
+        let chars: Vec<char> = vis.chars().collect();
+        chars.iter().enumerate().map(|(k, &c)| {
+            let w = self.rtl_char_width(scale, c);
+            if self.char_spacing == 0 || is_arabic_mark(c) {
+                return w;
+            }
+            match chars[k + 1..].iter().find(|n| !is_arabic_mark(**n)) {
+                Some(&right) if !joins_left(right) => w + self.char_spacing,
+                _ => w,
+            }
+        }).collect()
+    }
+    /// Whether some face can draw `c` (whitespace and bidi marks are never drawn).
+    fn covers(&self, c: char) -> bool {
+        c.is_whitespace() || c.is_control() || is_bidi_mark(c)
+            || self.arabic.glyph_id(c).0 != 0 || self.latin.glyph_id(c).0 != 0
     }
 }
-fn draw_mixed_rtl_center(img: &mut RgbImage, font: &FontRef, scale: PxScale, logical: &str, paper_w: i32, y: i32) {
-    let shaped = shape(logical);
-    let mut runs: Vec<(bool, String, i32)> = Vec::new();
-    let mut cur = String::new();
-    let mut cur_is_ltr = None::<bool>;
-    for ch in shaped.chars() {
-        let ltr = is_ltr_char(ch);
-        match cur_is_ltr {
-            None => { cur_is_ltr = Some(ltr); cur.push(ch); }
-            Some(kind) if kind == ltr => cur.push(ch),
-            Some(_) => {
-                let w = if cur_is_ltr.unwrap() { text_size(scale, font, &cur).0 as i32 }
-                        else { cur.chars().map(|c| text_size(scale, font, &c.to_string()).0 as i32).sum() };
-                runs.push((cur_is_ltr.unwrap(), cur.clone(), w));
-                cur.clear(); cur_is_ltr = Some(ltr); cur.push(ch);
+fn load_font<'a>(bytes: &'a [u8], path: Option<&str>) -> Result<FontRef<'a>, String> {
+    FontRef::try_from_slice(bytes).map_err(|e| format!("font '{}': {}", path.unwrap_or("bundled"), e))
+}
+
+/// Orders shaped text with the Unicode Bidi Algorithm in an RTL paragraph.
+/// Returns (is_rtl, text, width) runs in visual order, left to right. RTL runs come
+/// back reversed and mirrored, so every run is simply drawn left to right.
+fn visual_runs(ts: &Typesetter, scale: PxScale, shaped: &str) -> Vec<(bool, String, i32)> {
+    let info = BidiInfo::new(shaped, Some(Level::rtl()));
+    let mut out = Vec::new();
+    for para in &info.paragraphs {
+        let (levels, runs) = info.visual_runs(para, para.range.clone());
+        for run in runs {
+            let text: String = shaped[run.clone()].chars().filter(|c| !is_bidi_mark(*c)).collect();
+            let text = text.as_str();
+            if levels[run.start].is_rtl() {
+                let vis: String = text.chars().rev().map(mirror_char).collect();
+                let w = ts.rtl_advances(scale, &vis).iter().sum();
+                out.push((true, vis, w));
+            } else {
+                out.push((false, text.to_string(), ts.ltr_width(scale, text)));
             }
         }
     }
-    if !cur.is_empty() {
-        let w = if cur_is_ltr.unwrap() { text_size(scale, font, &cur).0 as i32 }
-                else { cur.chars().map(|c| text_size(scale, font, &c.to_string()).0 as i32).sum() };
-        runs.push((cur_is_ltr.unwrap(), cur.clone(), w));
+    out
+}
+/// Width in px that `draw_mixed_rtl_right` would use for `logical`.
+fn measure_mixed(ts: &Typesetter, scale: PxScale, logical: &str) -> i32 {
+    visual_runs(ts, scale, &ts.shape(logical)).iter().map(|r| r.2).sum()
+}
+/// Draws visual runs left to right starting at `x_left`.
+/// RTL runs go glyph by glyph in the Arabic face; LTR runs as one string.
+fn draw_runs(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, runs: Vec<(bool, String, i32)>, x_left: i32, y: i32) {
+    let mut x = x_left;
+    for (is_rtl, seg, w) in runs.into_iter() {
+        if is_rtl {
+            for (c, advance) in seg.chars().zip(ts.rtl_advances(scale, &seg)) {
+                draw_crisp(img, &c.to_string(), x, y, scale, &ts.arabic);
+                x += advance;
+            }
+        } else {
+            draw_crisp(img, &seg, x, y, scale, ts.ltr_font(&seg));
+            x += w;
+        }
     }
+}
+fn draw_mixed_rtl_right(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, logical: &str, x_right: i32, y: i32) {
+    let runs = visual_runs(ts, scale, &ts.shape(logical));
+    let total_w: i32 = runs.iter().map(|r| r.2).sum();
+    draw_runs(img, ts, scale, runs, x_right - total_w, y);
+}
+/// Centered between the `left` and `right` x bounds.
+fn draw_mixed_rtl_center(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, logical: &str, left: i32, right: i32, y: i32) {
+    let runs = visual_runs(ts, scale, &ts.shape(logical));
     let total_w: i32 = runs.iter().map(|r| r.2).sum();
-    let mut right = (paper_w + total_w) / 2;
-    for (is_ltr, seg, w) in runs.into_iter() {
-        let start_x = right - w;
-        if is_ltr {
-            draw_ltr_right(img, font, scale, &seg, right, y);
+    draw_runs(img, ts, scale, runs, left + (right - left - total_w) / 2, y);
+}
+/// Blank rows between `y` and the topmost ink of `logical` drawn at `y`, from the
+/// glyph outlines (text is laid out with the baseline `ascent` below `y`).
+fn ink_top_offset(ts: &Typesetter, scale: PxScale, logical: &str) -> i32 {
+    let runs = visual_runs(ts, scale, &ts.shape(logical));
+    runs.iter()
+        .flat_map(|(is_rtl, seg, _)| {
+            let font = if *is_rtl { &ts.arabic } else { ts.ltr_font(seg) };
+            let ascent = font.as_scaled(scale).ascent();
+            seg.chars().filter_map(move |c| {
+                let glyph = font.glyph_id(c).with_scale_and_position(scale, ab_glyph::point(0.0, ascent));
+                font.outline_glyph(glyph).map(|o| o.px_bounds().min.y.floor() as i32)
+            })
+        })
+        .min()
+        .unwrap_or(0)
+}
+/// The longest logical prefix of `logical` that fits `max_w` with "…" after it. Cut on
+/// char boundaries before shaping, so the last kept letter takes its final form.
+fn ellipsize(ts: &Typesetter, scale: PxScale, logical: &str, max_w: i32) -> String {
+    if measure_mixed(ts, scale, logical) <= max_w {
+        return logical.to_string();
+    }
+    logical.char_indices().rev()
+        .map(|(i, _)| format!("{}…", logical[..i].trim_end()))
+        .find(|cut| measure_mixed(ts, scale, cut) <= max_w)
+        .unwrap_or_else(|| "…".into())
+}
+/// The largest scale (1 px steps, down to half of `scale`) at which `logical` fits `max_w`.
+fn shrink_to_fit(ts: &Typesetter, scale: PxScale, logical: &str, max_w: i32) -> PxScale {
+    let mut size = scale.y;
+    while size > scale.y / 2.0 && measure_mixed(ts, PxScale::from(size), logical) > max_w {
+        size -= 1.0;
+    }
+    PxScale::from(size)
+}
+/// `fonts.title`, or for a line wider than `max_w` the largest size that fits:
+/// scaled down in proportion, then 1 px at a time, never below `fonts.title_min`.
+fn title_scale(ts: &Typesetter, fonts: &Fonts, line: &str, max_w: i32) -> PxScale {
+    let full = measure_mixed(ts, PxScale::from(fonts.title), line);
+    if full <= max_w {
+        return PxScale::from(fonts.title);
+    }
+    let min = fonts.title_min.min(fonts.title);
+    let mut size = (fonts.title * max_w as f32 / full as f32).floor().max(min);
+    while size > min && measure_mixed(ts, PxScale::from(size), line) > max_w {
+        size -= 1.0;
+    }
+    PxScale::from(size)
+}
+/// Cuts `s` to `max` chars, ending in `mark` when anything was dropped.
+fn clip_chars(s: &str, max: usize, mark: &str) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let keep = max.saturating_sub(mark.chars().count());
+    format!("{}{}", s.chars().take(keep).collect::<String>().trim_end(), mark)
+}
+/// Breaks `logical` into lines no wider than `max_w`, only between words.
+/// Adjacent LTR words ("Coca Cola", "250 ml") stay on one line together.
+fn wrap_rtl(ts: &Typesetter, scale: PxScale, logical: &str, max_w: i32) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for word in logical.split_whitespace() {
+        let ltr = !word.chars().any(is_rtl_char);
+        match tokens.last_mut() {
+            Some(last) if ltr && !last.chars().any(is_rtl_char) => { last.push(' '); last.push_str(word); }
+            _ => tokens.push(word.to_string()),
+        }
+    }
+    let mut lines = Vec::new();
+    let mut cur = String::new();
+    for tok in tokens {
+        let candidate = if cur.is_empty() { tok.clone() } else { format!("{} {}", cur, tok) };
+        if cur.is_empty() || measure_mixed(ts, scale, &candidate) <= max_w {
+            cur = candidate;
         } else {
-            let chars: Vec<char> = seg.chars().collect();
-            let cw: Vec<i32> = chars.iter().map(|c| text_size(scale, font, &c.to_string()).0 as i32).collect();
-            let mut x = start_x;
-            for i in (0..chars.len()).rev() {
-                draw_crisp(img, &chars[i].to_string(), x, y, scale, font);
-                x += cw[i];
-            }
+            lines.push(std::mem::replace(&mut cur, tok));
         }
-        right -= w;
     }
+    if !cur.is_empty() || lines.is_empty() { lines.push(cur); }
+    lines
 }
-fn draw_ltr_right(img: &mut RgbImage, font: &FontRef, scale: PxScale, s: &str, x_right: i32, y: i32) {
-    let (w, _) = text_size(scale, font, s);
-    draw_crisp(img, s, x_right - w as i32, y, scale, font);
+/// Bold `draw_mixed_rtl_right`: the bold face when loaded, otherwise the regular
+/// face drawn twice 1 px apart (the copy goes left so the right edge stays put).
+fn draw_bold(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, logical: &str, x_right: i32, y: i32) {
+    match &ts.bold {
+        Some(b) => draw_mixed_rtl_right(img, b, scale, logical, x_right, y),
+        None => {
+            draw_mixed_rtl_right(img, ts, scale, logical, x_right - 1, y);
+            draw_mixed_rtl_right(img, ts, scale, logical, x_right, y);
+        }
+    }
+}
+/// Width in px that `draw_bold` covers for `logical`.
+fn measure_bold(ts: &Typesetter, scale: PxScale, logical: &str) -> i32 {
+    match &ts.bold {
+        Some(b) => measure_mixed(b, scale, logical),
+        None => measure_mixed(ts, scale, logical) + 1,
+    }
+}
+/// A line of an item's name: bidi-ordered like any RTL text, or as it reads with `force_ltr`.
+fn draw_name(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, item: &Item, line: &str, x_right: i32, y: i32) {
+    if item.force_ltr {
+        draw_ltr_right(img, ts, scale, line, x_right, y);
+    } else {
+        draw_mixed_rtl_right(img, ts, scale, line, x_right, y);
+    }
+}
+fn draw_ltr_right(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, s: &str, x_right: i32, y: i32) {
+    let w = ts.ltr_width(scale, s);
+    draw_crisp(img, s, x_right - w, y, scale, ts.ltr_font(s));
+}
+/// Strikes through a value drawn by `draw_mixed_rtl_right` with the same arguments.
+fn strike_right(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, s: &str, x_right: i32, y: i32) {
+    let w = measure_mixed(ts, scale, s);
+    let ascent = ts.ltr_font(s).as_scaled(scale).ascent();
+    let mid = y + (ascent - scale.y * 0.35) as i32; // about half the digit height above the baseline
+    draw_filled_rect_mut(img, Rect::at(x_right - w, mid).of_size(w.max(1) as u32, 2), Rgb([0,0,0]));
 }
-fn draw_ltr_center(img: &mut RgbImage, font: &FontRef, scale: PxScale, s: &str, paper_w: i32, y: i32) {
-    let (w, _) = text_size(scale, font, s);
-    let x = (paper_w - w as i32) / 2;
-    draw_crisp(img, s, x, y, scale, font);
+fn draw_ltr_center(img: &mut RgbImage, ts: &Typesetter, scale: PxScale, s: &str, left: i32, right: i32, y: i32) {
+    let w = ts.ltr_width(scale, s);
+    let x = left + (right - left - w) / 2;
+    draw_crisp(img, s, x, y, scale, ts.ltr_font(s));
 }
-fn draw_dotted(img: &mut RgbImage, y: i32, left: i32, right: i32) {
-    let y = y.max(0) as u32;
+/// Horizontal line of `dash`-px segments repeating every `period` px.
+fn draw_dashes(img: &mut RgbImage, y: i32, left: i32, right: i32, dash: i32, period: i32, thickness: u32) {
     let mut x = left.max(0);
     while x < right {
-        for dx in 0..3 {
-            if x + dx < right { img.put_pixel((x + dx) as u32, y, Rgb([0,0,0])); }
+        let w = dash.min(right - x);
+        draw_filled_rect_mut(img, Rect::at(x, y).of_size(w as u32, thickness.max(1)), Rgb([0,0,0]));
+        x += period;
+    }
+}
+fn draw_dotted(img: &mut RgbImage, y: i32, left: i32, right: i32, thickness: u32) {
+    draw_dashes(img, y, left, right, 3, 10, thickness);
+}
+/// Tiles `ch` from `left` to `right`, spreading the slack between copies so the
+/// last glyph ends exactly at `right`; the glyphs are vertically centered on `y`.
+fn draw_char_rule(img: &mut RgbImage, font: &FontRef, scale: PxScale, ch: char, left: i32, right: i32, y: i32) {
+    let s = ch.to_string();
+    let w = (text_size(scale, font, &s).0 as i32).max(1);
+    let span = right - left;
+    if span < w { return; }
+    let n = span / w;
+    let top = y - (scale.y / 2.0) as i32;
+    for i in 0..n {
+        let x = if n > 1 { left + i * (span - w) / (n - 1) } else { left };
+        draw_crisp(img, &s, x, top, scale, font);
+    }
+}
+/// A separator in `style` at the layout's thickness; `Chars` rules use the item font size.
+fn draw_separator(img: &mut RgbImage, ts: &Typesetter, layout: &Layout, style: SeparatorStyle, y: i32, left: i32, right: i32) {
+    let t = layout.separator_thickness.max(1);
+    match style {
+        SeparatorStyle::Dotted => draw_dotted(img, y, left, right, t),
+        SeparatorStyle::Dashed => draw_dashes(img, y, left, right, 12, 18, t),
+        SeparatorStyle::Solid => draw_dashes(img, y, left, right, right - left, right - left, t),
+        SeparatorStyle::Double => {
+            draw_dashes(img, y, left, right, right - left, right - left, t);
+            draw_dashes(img, y + t as i32 + 2, left, right, right - left, right - left, t);
+        }
+        SeparatorStyle::Chars(ch) => {
+            let font = ts.ltr_font(&ch.to_string());
+            draw_char_rule(img, font, PxScale::from(layout.fonts.item), ch, left, right, y);
         }
-        x += 10;
     }
 }
 
-// ---------------- Rendering ----------------
+/// Amount to `decimals` places with optional thousands grouping in the locale's digits:
+/// 1234.5 at 2 → "1234.50", "1,234.50" or "١٬٢٣٤٫٥٠".
+fn format_amount(value: f32, decimals: u8, locale: NumberLocale, grouping: bool) -> String {
+    let raw = format!("{:.*}", decimals as usize, value);
+    let (sign, digits) = match raw.strip_prefix('-') {
+        Some(d) => ("-", d),
+        None => ("", raw.as_str()),
+    };
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    let mut grouped = String::new();
+    for (i, c) in int.chars().enumerate() {
+        if grouping && i > 0 && (int.len() - i) % 3 == 0 { grouped.push(','); }
+        grouped.push(c);
+    }
+    let western = if frac.is_empty() { format!("{}{}", sign, grouped) } else { format!("{}{}.{}", sign, grouped, frac) };
+    localize_digits(&western, locale)
+}
+/// Attaches the currency to a formatted amount as its own RTL run (an RLM keeps
+/// Latin codes like "EGP" from merging with the digits), so bidi puts it on the
+/// requested side of the number.
+fn with_currency(amount: String, currency: Option<&str>, position: CurrencyPosition) -> String {
+    match currency.map(str::trim).filter(|c| !c.is_empty()) {
+        None => amount,
+        Some(c) => match position {
+            CurrencyPosition::After => format!("{} \u{200F}{}\u{200F}", amount, c),
+            CurrencyPosition::Before => format!("\u{200F}{}\u{200F} {}", c, amount),
+        },
+    }
+}
+/// Swaps Western digits and separators for the locale's own.
+fn localize_digits(s: &str, locale: NumberLocale) -> String {
+    match locale {
+        NumberLocale::Western => s.to_string(),
+        NumberLocale::ArabicIndic => s.chars()
+            .map(|c| match c {
+                '0'..='9' => char::from_u32(0x0660 + (c as u32 - '0' as u32)).unwrap_or(c),
+                '.' => '\u{066B}',
+                ',' => '\u{066C}',
+                _ => c,
+            })
+            .collect(),
+    }
+}
 
-fn render_receipt(data: &ReceiptData, layout: &Layout) -> GrayImage {
-    let paper_w = layout.paper_width_px as i32;
-    let mut img: RgbImage = ImageBuffer::from_pixel(layout.paper_width_px, 1800, Rgb([255,255,255]));
-    let margin_h = layout.margin_h;
-    let inner_w = paper_w - margin_h*2;
-    let right_edge = margin_h + inner_w;
-    let mut y = layout.margin_top;
+// ---------------- Amount in words (tafqit) ----------------
 
-    let font_bytes = include_bytes!("../fonts/NotoSansArabic-Regular.ttf");
-    let font = FontRef::try_from_slice(font_bytes).expect("font");
+const ONES: [&str; 10] = ["", "واحد", "اثنان", "ثلاثة", "أربعة", "خمسة", "ستة", "سبعة", "ثمانية", "تسعة"];
+const TEENS: [&str; 10] = [
+    "عشرة", "أحد عشر", "اثنا عشر", "ثلاثة عشر", "أربعة عشر",
+    "خمسة عشر", "ستة عشر", "سبعة عشر", "ثمانية عشر", "تسعة عشر",
+];
+const TENS: [&str; 10] = ["", "", "عشرون", "ثلاثون", "أربعون", "خمسون", "ستون", "سبعون", "ثمانون", "تسعون"];
+const HUNDREDS: [&str; 10] = [
+    "", "مائة", "مائتان", "ثلاثمائة", "أربعمائة", "خمسمائة", "ستمائة", "سبعمائة", "ثمانمائة", "تسعمائة",
+];
 
-    // Title
-    let title_scale = PxScale::from(layout.fonts.title);
-    draw_mixed_rtl_center(&mut img, &font, title_scale, &data.store_name, paper_w, y);
-    y += layout.fonts.title as i32 - 8;
+/// A counted noun's forms: one, two (dual), 3–10 (plural), 11–99 (accusative singular).
+struct Noun {
+    one: &'static str,
+    two: &'static str,
+    few: &'static str,
+    many: &'static str,
+}
 
-    // Date/Time
-    draw_mixed_rtl_center(&mut img, &font, PxScale::from(layout.fonts.header_dt), &data.date_time_line, paper_w, y);
-    y += layout.fonts.header_dt as i32 + 2;
+const THOUSAND: Noun = Noun { one: "ألف", two: "ألفان", few: "آلاف", many: "ألفًا" };
+const MILLION: Noun = Noun { one: "مليون", two: "مليونان", few: "ملايين", many: "مليونًا" };
 
-    // Receipt number
-    draw_ltr_center(&mut img, &font, PxScale::from(layout.fonts.header_no), &data.invoice_no, paper_w, y);
-    y += layout.fonts.header_no as i32 + 2;
+/// Main unit and, where its grammar matches (masculine), the hundredths subunit,
+/// for the currencies we know by name or code.
+fn currency_nouns(currency: &str) -> Option<(Noun, Option<Noun>)> {
+    let fils = Noun { one: "فلس", two: "فلسان", few: "فلوس", many: "فلسًا" };
+    match currency.trim() {
+        "جنيه" | "ج.م" | "ج" | "EGP" => Some((
+            Noun { one: "جنيه", two: "جنيهان", few: "جنيهات", many: "جنيهًا" },
+            Some(Noun { one: "قرش", two: "قرشان", few: "قروش", many: "قرشًا" }),
+        )),
+        "ريال" | "ر.س" | "SAR" => Some((Noun { one: "ريال", two: "ريالان", few: "ريالات", many: "ريالًا" }, None)),
+        "دينار" | "د.ك" | "KWD" | "د.أ" | "JOD" => Some((
+            Noun { one: "دينار", two: "ديناران", few: "دنانير", many: "دينارًا" },
+            Some(fils),
+        )),
+        "درهم" | "د.إ" | "AED" => Some((Noun { one: "درهم", two: "درهمان", few: "دراهم", many: "درهمًا" }, Some(fils))),
+        "دولار" | "$" | "USD" => Some((
+            Noun { one: "دولار", two: "دولاران", few: "دولارات", many: "دولارًا" },
+            Some(Noun { one: "سنت", two: "سنتان", few: "سنتات", many: "سنتًا" }),
+        )),
+        _ => None,
+    }
+}
 
-    // Columns (RTL)
-    let w_name  = (inner_w as f32 * layout.cols[0]) as i32;
-    let w_qty   = (inner_w as f32 * layout.cols[1]) as i32;
-    let w_price = (inner_w as f32 * layout.cols[2]) as i32;
-    let w_total = (inner_w as f32 * layout.cols[3]) as i32;
+/// 1–999 in words ("مائة وخمسة وعشرون"); empty for 0.
+fn words_below_1000(n: u64) -> String {
+    let (h, rest) = ((n / 100) as usize, (n % 100) as usize);
+    let rest = match rest {
+        0 => String::new(),
+        1..=9 => ONES[rest].to_string(),
+        10..=19 => TEENS[rest - 10].to_string(),
+        _ if rest % 10 == 0 => TENS[rest / 10].to_string(),
+        _ => format!("{} و{}", ONES[rest % 10], TENS[rest / 10]),
+    };
+    match (h, rest.is_empty()) {
+        (0, _) => rest,
+        (_, true) => HUNDREDS[h].to_string(),
+        (_, false) => format!("{} و{}", HUNDREDS[h], rest),
+    }
+}
 
-    let r_name  = right_edge;
-    let r_qty   = r_name  - w_name;
-    let r_price = r_qty   - w_qty;
-    let r_total = r_price - w_total;
+/// The noun form that follows the number `n` (3–10 plural, 11–99 accusative, else singular).
+fn noun_form(n: u64, noun: &Noun) -> &'static str {
+    match n % 100 {
+        3..=10 => noun.few,
+        11..=99 => noun.many,
+        _ => noun.one,
+    }
+}
 
-    let s_head = PxScale::from(layout.fonts.header_cols);
-    draw_mixed_rtl_right(&mut img, &font, s_head, "الصنف",  r_name,  y);
-    draw_mixed_rtl_right(&mut img, &font, s_head, "الكمية", r_qty,   y);
-    draw_mixed_rtl_right(&mut img, &font, s_head, "السعر",  r_price, y);
-    draw_mixed_rtl_right(&mut img, &font, s_head, "القيمة", r_total, y);
-    y += layout.row_gap - 6;
+/// `n` things: "ألف", "ألفان", "ثلاثة آلاف", "أحد عشر ألفًا", "مائة ألف".
+fn count_of(n: u64, noun: &Noun) -> String {
+    match n {
+        1 => noun.one.to_string(),
+        2 => noun.two.to_string(),
+        _ => format!("{} {}", int_words(n), noun_form(n, noun)),
+    }
+}
 
-    // Items
-    let s_item = PxScale::from(layout.fonts.item);
-    for it in &data.items {
-        draw_mixed_rtl_right(&mut img, &font, s_item, &it.name,   r_name,  y);
-        draw_ltr_right(&mut img,      &font, s_item, &it.qty_str, r_qty,   y);                 // qty as-is
-        draw_ltr_right(&mut img,      &font, s_item, &format!("{:.2}", it.price), r_price, y); // price 2dp
-        draw_ltr_right(&mut img,      &font, s_item, &format!("{:.2}", it.total), r_total, y); // item total 2dp
-        y += layout.row_gap;
+/// A whole number in words, thousands and millions joined with "و".
+fn int_words(n: u64) -> String {
+    if n == 0 {
+        return "صفر".into();
     }
+    let mut parts = Vec::new();
+    let (millions, thousands, rest) = (n / 1_000_000, n / 1000 % 1000, n % 1000);
+    if millions > 0 { parts.push(count_of(millions, &MILLION)); }
+    if thousands > 0 { parts.push(count_of(thousands, &THOUSAND)); }
+    if rest > 0 { parts.push(words_below_1000(rest)); }
+    parts.join(" و")
+}
 
-    // Dotted line
-    y += 18;
-    draw_dotted(&mut img, y, margin_h, paper_w - margin_h);
-    y += 12;
+/// An amount of money: "جنيه واحد", "جنيهان", "ثلاثة جنيهات", "مائتان وخمسون جنيهًا".
+fn money_words(n: u64, noun: &Noun) -> String {
+    match n {
+        0 => format!("صفر {}", noun.one),
+        1 => format!("{} واحد", noun.one),
+        _ => count_of(n, noun),
+    }
+}
 
-    // Discount (optional)
-    if data.discount > 0.0001 {
-        let gap = 12;
-        let label = "الخصم";
-        let (lw, _) = text_size(PxScale::from(layout.fonts.total_label), &font, &shape(label));
-        let right = right_edge;
-        draw_ltr_right(&mut img, &font, PxScale::from(layout.fonts.total_label),
-                       &format!("{:.2}", data.discount), right - lw as i32 - gap, y);
-        draw_mixed_rtl_right(&mut img, &font, PxScale::from(layout.fonts.total_label), label, right, y);
-        y += layout.row_gap - 6;
+/// The amount spelled out for formal invoices: "فقط مائتان وخمسون جنيهًا لا غير".
+/// Hundredths use the subunit where known ("وخمسون قرشًا"), otherwise "و50/100";
+/// an unknown currency is written after the number as given.
+fn amount_to_arabic_words(value: f32, currency: &str) -> String {
+    let hundredths = (value.abs() as f64 * 100.0).round() as u64;
+    let (whole, cents) = (hundredths / 100, hundredths % 100);
+    let nouns = currency_nouns(currency);
+    let mut words = match &nouns {
+        Some((unit, _)) => money_words(whole, unit),
+        None if currency.trim().is_empty() => int_words(whole),
+        None => format!("{} {}", int_words(whole), currency.trim()),
+    };
+    if cents > 0 {
+        match nouns.as_ref().and_then(|(_, sub)| sub.as_ref()) {
+            Some(sub) => words += &format!(" و{}", money_words(cents, sub)),
+            None => words += &format!(" و{}/100", cents),
+        }
     }
+    let sign = if value < 0.0 && hundredths > 0 { "سالب " } else { "" };
+    format!("فقط {}{} لا غير", sign, words)
+}
 
-    // Total (display as provided)
+/// Totals-style row: `label` right-aligned at `right`, `value` just to its left.
+fn draw_amount_row(img: &mut RgbImage, ts: &Typesetter, layout: &Layout, label: &str, value: &str, right: i32, y: i32) {
     let gap = 12;
-    let label = "إجمالي الفاتورة";
-    let (lw, _) = text_size(PxScale::from(layout.fonts.total_label), &font, &shape(label));
-    let right = right_edge;
+    let scale = PxScale::from(layout.fonts.total_label);
+    let lw = measure_mixed(ts, scale, label);
+    draw_mixed_rtl_right(img, ts, scale, value, right - lw - gap, y); // numbers come out as one LTR run
+    draw_mixed_rtl_right(img, ts, scale, label, right, y);
+}
 
-    draw_ltr_right(&mut img, &font, PxScale::from(layout.fonts.total_value),
-                   &format!("{:.2}", data.total), right - lw as i32 - gap, y - 10);
-    draw_mixed_rtl_right(&mut img, &font, PxScale::from(layout.fonts.total_label), label, right, y);
-    y += layout.row_gap;
+/// Draws `text` as a QR code centered between `left` and `right`; returns the height used.
+fn draw_qr(img: &mut RgbImage, text: &str, ec: QrEc, module: u32, left: i32, right: i32, y: i32) -> Result<i32, String> {
+    let code = QrCode::with_error_correction_level(text.as_bytes(), ec.level()).map_err(|e| format!("QR '{}': {}", text, e))?;
+    let n = code.width() as i32;
+    let m = module.max(1) as i32;
+    let x0 = left + (right - left - n * m) / 2;
+    for (i, c) in code.to_colors().into_iter().enumerate() {
+        if c == Color::Dark {
+            let (cx, cy) = (i as i32 % n, i as i32 / n);
+            draw_filled_rect_mut(img, Rect::at(x0 + cx * m, y + cy * m).of_size(m as u32, m as u32), Rgb([0,0,0]));
+        }
+    }
+    Ok(n * m)
+}
 
-    // Footer
-    draw_mixed_rtl_center(&mut img, &font, PxScale::from(layout.fonts.footer), &data.footer_address,  paper_w, y);
-    y += layout.fonts.footer as i32 + 2;
+/// Logo (right) and QR (left) side by side, each fitted to half the width between
+/// `left` and `right` and bottom-aligned on a shared baseline; returns the band height.
+fn draw_header_band(img: &mut RgbImage, logo: Option<&GrayImage>, qr: Option<(&str, QrEc)>, left: i32, right: i32, y: i32) -> Result<i32, String> {
+    let gap = 16;
+    let half = ((right - left - gap) / 2).max(1);
+    let logo = logo.map(|l| {
+        let h = (l.height() as f32 * half as f32 / l.width().max(1) as f32).round().max(1.0) as u32;
+        image::imageops::resize(l, half as u32, h, FilterType::Triangle)
+    });
+    let qr = match qr {
+        Some((text, ec)) => Some((text, ec, QrCode::with_error_correction_level(text.as_bytes(), ec.level()).map_err(|e| format!("QR '{}': {}", text, e))?.width() as i32)),
+        None => None,
+    };
+    let module = qr.map_or(1, |(_, _, n)| (half / n).max(1));
+    let logo_h = logo.as_ref().map_or(0, |l| l.height() as i32);
+    let qr_h = qr.map_or(0, |(_, _, n)| n * module);
+    let band_h = logo_h.max(qr_h);
+    if let Some(l) = &logo {
+        draw_gray(img, l, right - half, y + band_h - logo_h);
+    }
+    if let Some((text, ec, _)) = qr {
+        draw_qr(img, text, ec, module as u32, left, left + half, y + band_h - qr_h)?;
+    }
+    Ok(band_h)
+}
 
-    draw_mixed_rtl_center(&mut img, &font, PxScale::from(layout.fonts.footer), &data.footer_delivery, paper_w, y);
-    y += layout.fonts.footer as i32 + 2;
+/// Loads a logo as grayscale (transparency flattened onto white),
+/// downscaled to `max_w` when wider while keeping the aspect ratio.
+fn load_logo(path: &str, max_w: u32) -> Result<GrayImage, String> {
+    let img = image::open(path).map_err(|e| format!("logo '{}': {}", path, e))?;
+    Ok(to_gray_fit(img, max_w))
+}
+/// Grayscale, transparency flattened onto white, scaled down to at most `max_w` px wide.
+fn to_gray_fit(img: image::DynamicImage, max_w: u32) -> GrayImage {
+    let img = if img.width() > max_w { img.resize(max_w, u32::MAX, FilterType::Triangle) } else { img };
+    let rgba = img.to_rgba8();
+    GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let l = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+        let a = a as f32 / 255.0;
+        Luma([(l * a + 255.0 * (1.0 - a)).round() as u8])
+    })
+}
+/// Pre-binarizes the logo to pure black/white so the print-time `threshold`
+/// (tuned for text) leaves it alone: dithered when `dither`, else hard-thresholded
+/// at `logo_threshold` when one is set.
+fn binarize_logo(logo: GrayImage, layout: &Layout) -> GrayImage {
+    let threshold = layout.logo_threshold.unwrap_or(layout.threshold);
+    if layout.dither {
+        dither_floyd_steinberg(&logo, threshold)
+    } else if layout.logo_threshold.is_some() {
+        threshold_image(&logo, threshold)
+    } else {
+        logo
+    }
+}
+/// Floyd–Steinberg error diffusion to 0/255; pixels `<= threshold` start out black.
+fn dither_floyd_steinberg(gray: &GrayImage, threshold: u8) -> GrayImage {
+    let (w, h) = (gray.width() as usize, gray.height() as usize);
+    let mut buf: Vec<i32> = gray.pixels().map(|Luma([v])| *v as i32).collect();
+    let mut out = GrayImage::new(gray.width(), gray.height());
+    for y in 0..h {
+        for x in 0..w {
+            let old = buf[y * w + x];
+            let new = if old <= threshold as i32 { 0 } else { 255 };
+            out.put_pixel(x as u32, y as u32, Luma([new as u8]));
+            let err = old - new;
+            let mut spread = |dx: isize, dy: usize, k: i32| {
+                let nx = x as isize + dx;
+                if nx >= 0 && (nx as usize) < w && y + dy < h {
+                    buf[(y + dy) * w + nx as usize] += err * k / 16;
+                }
+            };
+            spread(1, 0, 7);
+            spread(-1, 1, 3);
+            spread(0, 1, 5);
+            spread(1, 1, 1);
+        }
+    }
+    out
+}
+/// Copies a grayscale image onto the canvas at (x, y), clipped to the canvas.
+fn draw_gray(img: &mut RgbImage, src: &GrayImage, x: i32, y: i32) {
+    for (sx, sy, Luma([v])) in src.enumerate_pixels() {
+        let (dx, dy) = (x + sx as i32, y + sy as i32);
+        if dx >= 0 && dy >= 0 && (dx as u32) < img.width() && (dy as u32) < img.height() {
+            img.put_pixel(dx as u32, dy as u32, Rgb([*v, *v, *v]));
+        }
+    }
+}
 
-    if !data.footer_phones.is_empty() {
-        draw_ltr_center(&mut img, &font, PxScale::from(layout.fonts.footer_phones), &data.footer_phones, paper_w, y);
-        // ✅ Advance y so cropping includes the phones line
-        y += layout.fonts.footer_phones as i32 + 2;
+/// Light "gray" for row shading: a sparse dot grid (one dot per `pitch`² px, rows offset)
+/// drawn straight in black, since a flat light gray would just threshold away to white.
+fn draw_shade(img: &mut RgbImage, left: i32, top: i32, right: i32, bottom: i32, dot: u32) {
+    let dot = dot.max(1) as i32;
+    let pitch = dot * 4;
+    for y in top.max(0)..bottom.min(img.height() as i32) {
+        let row = (y - top) / dot;
+        if row % 4 != 0 { continue; }
+        let offset = if (row / 4) % 2 == 0 { 0 } else { pitch / 2 };
+        for x in left.max(0)..right.min(img.width() as i32) {
+            if (x - left + offset) % pitch < dot {
+                img.put_pixel(x as u32, y as u32, Rgb([0,0,0]));
+            }
+        }
     }
+}
 
-    y += layout.margin_bottom;
+// ---------------- Native barcodes (GS k) ----------------
 
-    // Crop & grayscale
-    let used_h = (y as u32).min(1798);
-    image::DynamicImage::ImageRgb8(img)
-        .crop_imm(0, 0, layout.paper_width_px, used_h)
-        .to_luma8()
+/// Digits without their check digit, after validating the check digit if present.
+/// `len` is the payload length (12 for EAN-13, 11 for UPC-A).
+fn gtin_payload(code: &str, len: usize, name: &str) -> Result<String, String> {
+    if !code.chars().all(|c| c.is_ascii_digit()) || (code.len() != len && code.len() != len + 1) {
+        return Err(format!("{} needs {} or {} digits, got '{}'", name, len, len + 1, code));
+    }
+    let digits: Vec<u32> = code.bytes().map(|b| (b - b'0') as u32).collect();
+    // Weights alternate 3,1,... starting from the digit next to the check digit.
+    let sum: u32 = digits[..len].iter().rev().enumerate()
+        .map(|(i, d)| if i % 2 == 0 { d * 3 } else { *d })
+        .sum();
+    let check = (10 - sum % 10) % 10;
+    if code.len() == len + 1 && digits[len] != check {
+        return Err(format!("{} '{}' has check digit {}, expected {}", name, code, digits[len], check));
+    }
+    Ok(code[..len].to_string())
 }
 
-// Pack ESC * 24-dot bands (m=33)
-fn pack_esc_star_24(gray: &GrayImage, y0: u32, threshold: u8) -> Vec<u8> {
-    let w = gray.width();
-    let h = gray.height();
-    let mut band = Vec::with_capacity((w * 3) as usize);
-    for x in 0..w {
-        for byte in 0..3 {
-            let mut b = 0u8;
-            for bit in 0..8 {
-                let yy = y0 + (byte * 8 + bit) as u32;
-                if yy < h {
-                    let Luma([pix]) = *gray.get_pixel(x, yy);
-                    if pix <= threshold { b |= 1 << (7 - bit); }
-                }
+/// ESC/POS bytes printing `code` centered as a barcode (no HRI text; the number is already drawn).
+fn barcode_command(spec: &BarcodeSpec, code: &str) -> Result<Vec<u8>, String> {
+    let (m, payload) = match spec.symbology {
+        Symbology::Code128 => {
+            if code.is_empty() || !code.bytes().all(|b| (0x20..0x7F).contains(&b)) {
+                return Err(format!("Code128 needs printable ASCII, got '{}'", code));
             }
-            band.push(b);
+            (73u8, format!("{{B{}", code)) // code set B
         }
+        Symbology::Ean13 => (67u8, gtin_payload(code, 12, "EAN-13")?),
+        Symbology::Upca => (65u8, gtin_payload(code, 11, "UPC-A")?),
+    };
+    if payload.len() > 255 {
+        return Err(format!("barcode data too long ({} bytes)", payload.len()));
     }
-    band
+    let mut out = vec![
+        0x1D, 0x48, 0x00,                              // GS H: no HRI
+        0x1D, 0x68, spec.height.max(1),                // GS h: height
+        0x1D, 0x77, spec.module_width.clamp(2, 6),     // GS w: module width
+        0x1B, 0x61, 0x01,                              // ESC a: center
+        0x1D, 0x6B, m, payload.len() as u8,            // GS k m n
+    ];
+    out.extend_from_slice(payload.as_bytes());
+    out.extend_from_slice(&[0x1B, 0x61, 0x00]);        // back to left
+    Ok(out)
 }
 
-// ---------------- Tauri Command ----------------
+/// Bytes a model 1 version 14 QR holds at L, M, Q, H: its data codewords (ISO 18004:2000
+/// Annex M: 489, 368, 310, 210) less the 4-bit model 1 header, byte mode and 16-bit count.
+const QR_MODEL1_MAX_BYTES: [usize; 4] = [486, 365, 307, 207];
 
-#[tauri::command]
-async fn print_receipt(
-    title: String,
-    time: String,
-    number: String,
-    items: Vec<FrontendItem>,
-    total: f32,           // overall total (printed as-is)
-    discount: Option<f32>,
-    footer: FrontendFooter,
-) -> Result<String, String> {
-    let mapped_items: Vec<Item> = items.into_iter()
-        .map(|i| Item {
-            name: i.name,
-            qty_str: i.qty.to_display(), // preserve input representation
-            price: i.price,
-            total: i.total,
-        })
-        .collect();
+/// ESC/POS bytes printing `text` centered as a QR code: `GS ( k` model, module size,
+/// error correction, store, print. Errors when the data outgrows the largest version
+/// the model has (14 for model 1, 40 for model 2) at the chosen error correction.
+fn qr_command(spec: &QrSpec, text: &str) -> Result<Vec<u8>, String> {
+    if !matches!(spec.model, 1 | 2) {
+        return Err(format!("QR model must be 1 or 2, got {}", spec.model));
+    }
+    if !(1..=16).contains(&spec.module_size) {
+        return Err(format!("QR module_size must be 1-16, got {}", spec.module_size));
+    }
+    if text.is_empty() {
+        return Err("QR data is empty".into());
+    }
+    let fits = match spec.model {
+        1 => text.len() <= QR_MODEL1_MAX_BYTES[spec.ec as usize],
+        _ => QrCode::with_version(text.as_bytes(), Version::Normal(40), spec.ec.level()).is_ok(),
+    };
+    if !fits {
+        return Err(format!("QR data ({} bytes) does not fit a model {} QR at error correction {:?}", text.len(), spec.model, spec.ec));
+    }
+    let ec = match spec.ec { QrEc::L => 48, QrEc::M => 49, QrEc::Q => 50, QrEc::H => 51 };
+    let store = text.len() + 3;
+    let mut out = vec![
+        0x1B, 0x61, 0x01,                                              // ESC a: center
+        0x1D, 0x28, 0x6B, 0x04, 0x00, 0x31, 0x41, 48 + spec.model, 0x00, // fn 165: model
+        0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x43, spec.module_size,    // fn 167: module size
+        0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x45, ec,                  // fn 169: error correction
+        0x1D, 0x28, 0x6B, (store & 0xFF) as u8, (store >> 8) as u8, 0x31, 0x50, 0x30, // fn 180: store
+    ];
+    out.extend_from_slice(text.as_bytes());
+    out.extend_from_slice(&[0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x51, 0x30]); // fn 181: print
+    out.extend_from_slice(&[0x0A, 0x1B, 0x61, 0x00]);                         // end the line, back to left
+    Ok(out)
+}
 
-    let data = ReceiptData {
-        store_name: title,
-        date_time_line: time,
-        invoice_no: number,
-        items: mapped_items,
-        discount: discount.unwrap_or(0.0),
-        total,
-        footer_address: footer.address,
-        footer_delivery: footer.last_line,
-        footer_phones: footer.phones.unwrap_or_default(),
+// ---------------- Bitmap barcodes ----------------
+
+/// Quiet zone on each side of a bitmap barcode, in modules (EAN/UPC need 11, Code128 10).
+const BARCODE_QUIET_MODULES: i32 = 11;
+
+/// The barcode's bars as modules (1 = bar, 0 = space), guards included.
+fn barcode_modules(spec: &BarcodeSpec, code: &str) -> Result<Vec<u8>, String> {
+    use barcoders::sym::{code128::Code128, ean13::EAN13};
+    let encoded = match spec.symbology {
+        Symbology::Code128 => {
+            if code.is_empty() || !code.bytes().all(|b| (0x20..0x7F).contains(&b)) {
+                return Err(format!("Code128 needs printable ASCII, got '{}'", code));
+            }
+            Code128::new(format!("\u{0181}{}", code)).map(|b| b.encode()) // 'Ɓ': code set B
+        }
+        Symbology::Ean13 => EAN13::new(gtin_payload(code, 12, "EAN-13")?).map(|b| b.encode()),
+        // UPC-A is EAN-13 with a leading 0: same bars
+        Symbology::Upca => EAN13::new(format!("0{}", gtin_payload(code, 11, "UPC-A")?)).map(|b| b.encode()),
     };
+    encoded.map_err(|e| format!("barcode '{}': {}", code, e))
+}
 
-    let layout = Layout::default();
+/// Draws the modules as bars `module` px wide and `height` px tall, centered between
+/// `left` and `right` with the quiet zones kept clear; returns the height used.
+fn draw_barcode(img: &mut RgbImage, modules: &[u8], module: i32, height: i32, left: i32, right: i32, y: i32) -> Result<i32, String> {
+    let m = module.max(1);
+    let width = (modules.len() as i32 + 2 * BARCODE_QUIET_MODULES) * m;
+    if width > right - left {
+        return Err(format!("barcode is {} px wide with its quiet zones, the paper has {} px", width, right - left));
+    }
+    let x0 = left + (right - left - width) / 2 + BARCODE_QUIET_MODULES * m;
+    for (i, bar) in modules.iter().enumerate() {
+        if *bar == 1 {
+            draw_filled_rect_mut(img, Rect::at(x0 + i as i32 * m, y).of_size(m as u32, height.max(1) as u32), Rgb([0, 0, 0]));
+        }
+    }
+    Ok(height)
+}
 
-    let port = normalize_com_port(&get_com_port());
-    let baud = get_baud_rate();
-    let driver = SerialPortDriver::open(&port, baud, None)
-        .map_err(|e| format!("open {} @{}: {}", port, baud, e))?;
+// ---------------- Rendering ----------------
 
-    let mut obj = Printer::new(driver, Protocol::default(), None);
-    obj.debug_mode(None);
-    let mut p = obj.init().map_err(|e| e.to_string())?;
+/// Raw ESC/POS spliced into the raster stream before image row `row`.
+struct Splice {
+    row: u32,
+    bytes: Vec<u8>,
+}
 
-    let gray = render_receipt(&data, &layout);
+/// A rendered receipt: the bitmap plus native commands to interleave with it.
+struct Rendered {
+    image: GrayImage,
+    splices: Vec<Splice>,
+    missing: Vec<char>,         // characters drawn as `Layout.missing_glyph`
+    red: Vec<std::ops::Range<u32>>, // image rows printed in the second color, top to bottom
+}
 
-    // ESC * 24-dot double density
-    let w = gray.width();
-    let n = w as u16;
-    let nL = (n & 0xFF) as u8;
-    let nH = ((n >> 8) & 0xFF) as u8;
+/// Rejects an empty receipt when the layout says so; every render mode calls this first.
+fn check_items(data: &ReceiptData, layout: &Layout) -> Result<(), String> {
+    if data.items.is_empty() && matches!(layout.empty_items, EmptyItems::Reject) {
+        return Err("receipt has no items".into());
+    }
+    Ok(())
+}
 
-    let mut y0 = 0u32;
-    while y0 < gray.height() {
-        let band = pack_esc_star_24(&gray, y0, layout.threshold);
-        p = p.custom(&[0x1B, 0x2A, 33, nL, nH]).map_err(|e| e.to_string())?;
-        p = p.custom(&band).map_err(|e| e.to_string())?;
-        p = p.custom(&[0x0A]).map_err(|e| e.to_string())?;
-        y0 += 24;
+fn render_receipt(data: &ReceiptData, layout: &Layout) -> Result<GrayImage, String> {
+    compose_receipt(data, layout).map(|r| r.image)
+}
+
+/// Fonts and images a receipt needs, loaded once per render.
+struct Assets {
+    arabic_bytes: Cow<'static, [u8]>,
+    latin_bytes: Option<Cow<'static, [u8]>>,
+    bold_bytes: Option<Cow<'static, [u8]>>,
+    logo: Option<GrayImage>,
+}
+impl Assets {
+    fn load(logo_path: Option<&str>, layout: &Layout) -> Result<Self, String> {
+        let latin_bytes = match layout.latin_font_path.as_deref() {
+            Some(p) => Some(load_font_bytes(Some(p))?),
+            None => None,
+        };
+        let bold_bytes = match layout.bold_font_path.as_deref() {
+            Some(p) => Some(load_font_bytes(Some(p))?),
+            None => None,
+        };
+        let logo = match logo_path.filter(|s| !s.is_empty()) {
+            Some(path) => Some(binarize_logo(load_logo(path, layout.paper_width_px)?, layout)),
+            None => None,
+        };
+        Ok(Self { arabic_bytes: load_font_bytes(layout.font_path.as_deref())?, latin_bytes, bold_bytes, logo })
+    }
+    fn typesetter(&self, layout: &Layout) -> Result<Typesetter<'_>, String> {
+        let arabic = load_font(&self.arabic_bytes, layout.font_path.as_deref())?;
+        let latin = match &self.latin_bytes {
+            Some(b) => load_font(b, layout.latin_font_path.as_deref())?,
+            None => arabic.clone(),
+        };
+        let bold = match &self.bold_bytes {
+            Some(b) => {
+                let face = load_font(b, layout.bold_font_path.as_deref())?;
+                Some(Box::new(Typesetter {
+                    arabic: face.clone(),
+                    latin: face,
+                    reshaper: layout.reshape.reshaper(),
+                    bold: None,
+                    char_spacing: layout.char_spacing as i32,
+                }))
+            }
+            None => None,
+        };
+        Ok(Typesetter { arabic, latin, reshaper: layout.reshape.reshaper(), bold, char_spacing: layout.char_spacing as i32 })
+    }
+}
+
+/// With `supersample` N > 1 the whole layout is drawn N× larger (the logo upscaled
+/// with nearest neighbour) and the result box-filtered back to the paper width,
+/// so every position scales together and the columns stay aligned.
+fn compose_receipt(data: &ReceiptData, layout: &Layout) -> Result<Rendered, String> {
+    check_items(data, layout)?;
+    let (ss, mut work, assets) = working_scale(data, layout)?;
+    let ts = assets.typesetter(&work)?;
+    let (data, missing) = cover_missing(data, &mut work, &ts);
+    let data = data.as_ref();
+    let height = measure_receipt_height(data, &work, &ts, &assets)?;
+    if height / ss > MAX_RECEIPT_HEIGHT {
+        return Err(format!("receipt too long ({} items, {} px; the limit is {} px)", data.items.len(), height / ss, MAX_RECEIPT_HEIGHT));
+    }
+
+    let mut img: RgbImage = ImageBuffer::from_pixel(work.paper_width_px, height, Rgb([255,255,255]));
+    let mut splices = Vec::new();
+    let mut metrics = ReceiptMetrics::default();
+    draw_receipt(&mut img, data, &work, &ts, &assets, &mut splices, &mut metrics)?;
+    let mut image = image::DynamicImage::ImageRgb8(img).to_luma8();
+    if ss > 1 {
+        image = image::imageops::resize(&image, layout.paper_width_px, height.div_ceil(ss).max(1), FilterType::Triangle);
+        splices.iter_mut().for_each(|sp| sp.row /= ss);
+    }
+    let metrics = metrics.scaled_down(ss);
+    let red = metrics.red.iter().filter_map(|&(top, bottom)| inked_rows(&image, top, bottom, layout.threshold)).collect();
+    Ok(Rendered { image, splices, missing, red })
+}
+/// The rows between `top` and `bottom` that hold ink: the strip that can go out in
+/// another color without taking the empty space around the text with it.
+fn inked_rows(img: &GrayImage, top: i32, bottom: i32, threshold: u8) -> Option<std::ops::Range<u32>> {
+    let (top, bottom) = (top.max(0) as u32, (bottom.max(0) as u32).min(img.height()));
+    let inked = |y: &u32| (0..img.width()).any(|x| img.get_pixel(x, *y).0[0] <= threshold);
+    let first = (top..bottom).find(inked)?;
+    let last = (top..bottom).rev().find(inked)?;
+    Some(first..last + 1)
+}
+/// The supersampling factor, the layout scaled by it and the assets to draw with.
+fn working_scale(data: &ReceiptData, layout: &Layout) -> Result<(u32, Layout, Assets), String> {
+    let ss = layout.supersample.clamp(1, 3) as u32;
+    let work = if ss > 1 { layout.scaled(ss as f32) } else { layout.clone() };
+    let mut assets = Assets::load(data.logo_path.as_deref(), layout)?;
+    if ss > 1 {
+        assets.logo = assets.logo.map(|l| image::imageops::resize(&l, l.width() * ss, l.height() * ss, FilterType::Nearest));
+    }
+    Ok((ss, work, assets))
+}
+
+/// The receipt with every character no face can draw replaced by `missing_glyph`
+/// (or '?'), so it takes up room like any glyph instead of leaving a zero-width
+/// gap that throws off the run widths; plus the distinct characters replaced.
+/// The layout's printed labels and copy captions are checked and fixed in place too.
+fn cover_missing<'a>(data: &'a ReceiptData, layout: &mut Layout, ts: &Typesetter) -> (Cow<'a, ReceiptData>, Vec<char>) {
+    let mut missing: Vec<char> = Vec::new();
+    let mut check = |s: &str| {
+        for c in s.chars().filter(|c| !ts.covers(*c)) {
+            if !missing.contains(&c) { missing.push(c); }
+        }
+    };
+    check(&data.store_name);
+    if let Some(store) = &data.store {
+        check(&store.name);
+        store.lines().iter().for_each(|l| check(l));
+    }
+    check(&data.date_time_line);
+    check(&data.invoice_no);
+    for it in &data.items {
+        check(&it.name);
+        it.notes.iter().for_each(|n| check(n));
+        check(it.sku.as_deref().unwrap_or(""));
+    }
+    data.footer_lines.iter().for_each(|l| check(&l.text));
+    check(data.payment_method.as_deref().unwrap_or(""));
+    check(data.currency.as_deref().unwrap_or(""));
+    check(data.watermark.as_deref().unwrap_or(""));
+    data.stub.iter().flat_map(|s| &s.lines).for_each(|l| check(l));
+    check(layout.invoice_label.as_deref().unwrap_or(""));
+    check(layout.weight_label.as_deref().unwrap_or(""));
+    layout.copy_captions.iter().for_each(|c| check(c));
+    if missing.is_empty() {
+        return (Cow::Borrowed(data), missing);
+    }
+
+    let replacement = if ts.covers(layout.missing_glyph) { layout.missing_glyph } else { '?' };
+    let fix = |s: &mut String| *s = s.chars().map(|c| if missing.contains(&c) { replacement } else { c }).collect();
+    let mut data = data.clone();
+    fix(&mut data.store_name);
+    if let Some(store) = &mut data.store {
+        fix(&mut store.name);
+        for field in [&mut store.branch, &mut store.tax_id, &mut store.commercial_registry, &mut store.address, &mut store.phone] {
+            field.iter_mut().for_each(fix);
+        }
+    }
+    fix(&mut data.date_time_line);
+    fix(&mut data.invoice_no);
+    for it in &mut data.items {
+        fix(&mut it.name);
+        it.notes.iter_mut().for_each(fix);
+        it.sku.iter_mut().for_each(fix);
     }
+    data.footer_lines.iter_mut().for_each(|l| fix(&mut l.text));
+    data.payment_method.iter_mut().for_each(fix);
+    data.currency.iter_mut().for_each(fix);
+    data.watermark.iter_mut().for_each(fix);
+    data.stub.iter_mut().flat_map(|s| &mut s.lines).for_each(fix);
+    layout.invoice_label.iter_mut().for_each(fix);
+    layout.weight_label.iter_mut().for_each(fix);
+    layout.copy_captions.iter_mut().for_each(fix);
+    (Cow::Owned(data), missing)
+}
+/// "no glyph for U+1F600 '😀'" for the characters `cover_missing` replaced.
+fn missing_glyph_warning(missing: &[char]) -> Option<String> {
+    if missing.is_empty() {
+        return None;
+    }
+    let list: Vec<String> = missing.iter().map(|c| format!("U+{:04X} '{}'", *c as u32, c)).collect();
+    Some(format!("no glyph for {}", list.join(", ")))
+}
 
-    // feed & cut
-    p = p.custom(&[0x0A]).map_err(|e| e.to_string())?;
-    p = p.print_cut().map_err(|e| e.to_string())?;
-    p.print().map_err(|e| e.to_string())?;
-    Ok(format!("✅ Receipt printed on {}", port))
+/// Exact bitmap height for this receipt: the same drawing pass as the real
+/// render, run on a 1 px probe canvas where every draw call is clipped away.
+fn measure_receipt_height(data: &ReceiptData, layout: &Layout, ts: &Typesetter, assets: &Assets) -> Result<u32, String> {
+    let mut probe = RgbImage::new(layout.paper_width_px, 1);
+    let y = draw_receipt(&mut probe, data, layout, ts, assets, &mut Vec::new(), &mut ReceiptMetrics::default())?;
+    Ok(y.max(1) as u32)
 }
 
-// ---------------- App entry ----------------
+/// Where things land on a bitmap receipt, in paper px, for a layout designer.
+#[derive(Default, Serialize)]
+struct ReceiptMetrics {
+    width_px: u32,
+    height_px: u32,
+    columns: Vec<ColumnEdge>, // right to left
+    rows: Vec<RowMark>,       // top to bottom
+    separators: Vec<i32>,     // y of each separator rule
+    red: Vec<(i32, i32)>,     // (top, bottom) of each line drawn in the second color
+}
+#[derive(Serialize)]
+struct ColumnEdge {
+    column: &'static str, // "name", "qty", "price", "total", "tax"
+    left: i32,
+    right: i32,           // x where the column's right-aligned text ends
+}
+#[derive(Serialize)]
+struct RowMark {
+    row: String, // "title", "item 3", "total", "footer 2", ...
+    y: i32,      // the y the row's text is drawn at
+}
+impl ReceiptMetrics {
+    fn row(&mut self, row: impl Into<String>, y: i32) {
+        self.rows.push(RowMark { row: row.into(), y });
+    }
+    /// Back from the supersampled drawing to paper px.
+    fn scaled_down(mut self, ss: u32) -> Self {
+        let ss = ss as i32;
+        self.columns.iter_mut().for_each(|c| { c.left /= ss; c.right /= ss; });
+        self.rows.iter_mut().for_each(|r| r.y /= ss);
+        self.separators.iter_mut().for_each(|y| *y /= ss);
+        self.red.iter_mut().for_each(|(top, bottom)| { *top /= ss; *bottom = (*bottom + ss - 1) / ss; });
+        self
+    }
+}
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![ print_receipt ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+/// The geometry `render_receipt` would draw, from the same drawing pass run on a
+/// probe canvas, without producing the bitmap.
+fn measure_receipt(data: &ReceiptData, layout: &Layout) -> Result<ReceiptMetrics, String> {
+    let (ss, mut work, assets) = working_scale(data, layout)?;
+    let ts = assets.typesetter(&work)?;
+    let (data, _) = cover_missing(data, &mut work, &ts);
+    let mut metrics = ReceiptMetrics::default();
+    let mut probe = RgbImage::new(work.paper_width_px, 1);
+    let y = draw_receipt(&mut probe, &data, &work, &ts, &assets, &mut Vec::new(), &mut metrics)?;
+    let mut metrics = metrics.scaled_down(ss);
+    metrics.width_px = layout.paper_width_px;
+    metrics.height_px = (y.max(1) as u32).div_ceil(ss);
+    Ok(metrics)
+}
+
+/// Draws the whole receipt onto `img` and returns the y just past the content.
+fn draw_receipt(
+    img: &mut RgbImage,
+    data: &ReceiptData,
+    layout: &Layout,
+    ts: &Typesetter,
+    assets: &Assets,
+    splices: &mut Vec<Splice>,
+    metrics: &mut ReceiptMetrics,
+) -> Result<i32, String> {
+    let paper_w = layout.paper_width_px as i32;
+    let (margin_left, margin_right) = layout.margins();
+    let right_edge = paper_w - margin_right;
+    let inner_w = right_edge - margin_left;
+    let mut y = 0;
+    let amount_dp = |v: f32, dp: u8| format_amount(v, dp, layout.number_locale, layout.thousands_separator);
+    let amount = |v: f32| amount_dp(v, layout.total_decimals);
+    let money = |v: f32| with_currency(amount(v), data.currency.as_deref(), layout.currency_position);
+    let cell = |v: f32| if layout.currency_on_prices { money(v) } else { amount(v) };
+    let price_cell = |v: f32| {
+        let a = amount_dp(v, layout.price_decimals);
+        if layout.currency_on_prices { with_currency(a, data.currency.as_deref(), layout.currency_position) } else { a }
+    };
+
+    // Reprint stamp (optional): bold and larger than the total, before even the logo
+    if let Some(mark) = data.watermark() {
+        let scale = PxScale::from(layout.fonts.total_label * 1.25);
+        metrics.row("watermark", y);
+        draw_bold(img, ts, scale, mark, margin_left + (inner_w + measure_bold(ts, scale, mark)) / 2, y);
+        y += (scale.y * 1.4) as i32;
+    }
+
+    // Logo (optional), centered, or sharing a band with the QR; margin_top then applies below it
+    let side_by_side = matches!(layout.header_layout, HeaderLayout::SideBySide);
+    let qr = data.qr.as_deref().filter(|s| !s.is_empty());
+    if side_by_side && (assets.logo.is_some() || qr.is_some()) {
+        metrics.row("header_band", y);
+        y += draw_header_band(img, assets.logo.as_ref(), qr.map(|q| (q, data.qr_spec.ec)), margin_left, right_edge, y)?;
+    } else if let Some(logo) = &assets.logo {
+        metrics.row("logo", y);
+        draw_gray(img, logo, margin_left + (inner_w - logo.width() as i32) / 2, y);
+        y += logo.height() as i32;
+    }
+    let first_title_line = data.title().lines().next().unwrap_or("");
+    let first_scale = title_scale(ts, &layout.fonts, first_title_line, inner_w);
+    y += layout.margin_top.unwrap_or_else(|| -ink_top_offset(ts, first_scale, first_title_line));
+
+    // Title: one stacked line per '\n' (store name, then branch), each shrunk to fit if needed
+    for line in data.title().split('\n') {
+        let line = line.trim_end_matches('\r');
+        let scale = title_scale(ts, &layout.fonts, line, inner_w);
+        metrics.row("title", y);
+        draw_mixed_rtl_center(img, ts, scale, line, margin_left, right_edge, y);
+        y += scale.y as i32;
+    }
+    y -= 8;
+
+    // Store details (optional): tax ID, commercial registry, ... one labeled line each
+    if let Some(store) = &data.store {
+        let scale = PxScale::from(layout.fonts.header_dt);
+        for line in store.lines() {
+            metrics.row("store_info", y);
+            draw_mixed_rtl_center(img, ts, scale, &line, margin_left, right_edge, y);
+            y += layout.fonts.header_dt as i32 + 2;
+        }
+    }
+
+    // Date/Time
+    metrics.row("date_time", y);
+    draw_mixed_rtl_center(img, ts, PxScale::from(layout.fonts.header_dt), &data.date_time(), margin_left, right_edge, y);
+    y += layout.fonts.header_dt as i32 + 2;
+
+    // Receipt number
+    // (labeled, the label is an RTL run and the number an LTR run)
+    let s_no = PxScale::from(layout.fonts.header_no);
+    metrics.row("invoice_no", y);
+    match (data.invoice_line(layout), layout.invoice_align) {
+        (Cow::Borrowed(no), Align::Center) => draw_ltr_center(img, ts, s_no, no, margin_left, right_edge, y),
+        (Cow::Borrowed(no), Align::Right) => draw_ltr_right(img, ts, s_no, no, right_edge, y),
+        (Cow::Borrowed(no), Align::Left) => draw_ltr_right(img, ts, s_no, no, margin_left + ts.ltr_width(s_no, no), y),
+        (Cow::Owned(line), Align::Center) => draw_mixed_rtl_center(img, ts, s_no, &line, margin_left, right_edge, y),
+        (Cow::Owned(line), Align::Right) => draw_mixed_rtl_right(img, ts, s_no, &line, right_edge, y),
+        (Cow::Owned(line), Align::Left) => draw_mixed_rtl_right(img, ts, s_no, &line, margin_left + measure_mixed(ts, s_no, &line), y),
+    }
+    y += layout.fonts.header_no as i32 + 2;
+
+    // Barcode (optional): between the number and the columns, natively or drawn here
+    if let Some(spec) = &data.barcode {
+        match spec.render {
+            BarcodeRenderMode::Native => {
+                splices.push(Splice { row: y.max(0) as u32, bytes: barcode_command(spec, &data.invoice_no)? });
+            }
+            BarcodeRenderMode::Bitmap => {
+                let dot = layout.supersample.clamp(1, 3) as i32; // spec sizes are printer dots
+                let modules = barcode_modules(spec, &data.invoice_no)?;
+                metrics.row("barcode", y);
+                y += draw_barcode(img, &modules, spec.module_width.clamp(1, 6) as i32 * dot,
+                                  spec.height as i32 * dot, margin_left, right_edge, y + 4 * dot)?;
+                y += 12 * dot;
+            }
+        }
+    }
+
+    // Columns (RTL) in `col_order`; a kitchen ticket gives the price/value space to the name,
+    // an itemized-tax invoice adds a (tax) column at the far left, `show_item_index` a "#"
+    // column at the far right
+    let kitchen = matches!(layout.render_mode, RenderMode::Kitchen);
+    let itemized = matches!(layout.render_mode, RenderMode::ItemizedTax);
+    let w_tax = if itemized { (inner_w as f32 * layout.tax_col) as i32 } else { 0 };
+    let w_index = if layout.show_item_index { (inner_w as f32 * layout.index_col) as i32 } else { 0 };
+    let mut widths: [i32; 4] = std::array::from_fn(|i| ((inner_w - w_tax - w_index) as f32 * layout.cols[i]) as i32);
+    if kitchen {
+        for kind in [ColumnKind::Price, ColumnKind::Total] {
+            let freed = std::mem::take(&mut widths[layout.col_pos(kind)]);
+            widths[layout.col_pos(ColumnKind::Name)] += freed;
+        }
+    }
+    let mut rights = [right_edge - w_index; 4];
+    for i in 1..4 { rights[i] = rights[i - 1] - widths[i - 1]; }
+    let lefts: [i32; 4] = std::array::from_fn(|i| rights[i] - widths[i]);
+
+    let w_name  = widths[layout.col_pos(ColumnKind::Name)];
+    let w_qty   = widths[layout.col_pos(ColumnKind::Qty)];
+    let r_name  = rights[layout.col_pos(ColumnKind::Name)];
+    let r_qty   = rights[layout.col_pos(ColumnKind::Qty)];
+    let r_price = rights[layout.col_pos(ColumnKind::Price)];
+    let r_total = rights[layout.col_pos(ColumnKind::Total)];
+    let r_tax   = margin_left + w_tax;
+    if layout.show_item_index {
+        metrics.columns.push(ColumnEdge { column: "index", left: right_edge - w_index, right: right_edge });
+    }
+    for (i, kind) in layout.col_order.iter().enumerate() {
+        let column = match kind {
+            ColumnKind::Name => "name",
+            ColumnKind::Qty => "qty",
+            ColumnKind::Price if !kitchen => "price",
+            ColumnKind::Total if !kitchen => "total",
+            _ => continue,
+        };
+        metrics.columns.push(ColumnEdge { column, left: lefts[i], right: rights[i] });
+    }
+    if itemized {
+        metrics.columns.push(ColumnEdge { column: "tax", left: margin_left, right: r_tax });
+    }
+
+    // Column headers, each aligned within its column per `header_align`
+    let s_head = PxScale::from(layout.fonts.header_cols);
+    metrics.row("columns", y);
+    for (i, kind) in layout.col_order.iter().enumerate() {
+        let label = match kind {
+            ColumnKind::Name => "الصنف",
+            ColumnKind::Qty => "الكمية",
+            ColumnKind::Price if !kitchen => "السعر",
+            ColumnKind::Total if !kitchen => "القيمة",
+            _ => continue,
+        };
+        let w = measure_mixed(ts, s_head, label);
+        let x_right = match layout.header_align[i] {
+            Align::Right => rights[i],
+            Align::Center => lefts[i] + (widths[i] + w) / 2,
+            Align::Left => lefts[i] + w,
+        };
+        draw_mixed_rtl_right(img, ts, s_head, label, x_right, y);
+    }
+    if itemized {
+        draw_mixed_rtl_right(img, ts, s_head, "الضريبة", r_tax, y);
+    }
+    if layout.show_item_index {
+        draw_ltr_right(img, ts, s_head, "#", right_edge, y);
+    }
+    y += layout.row_gap - 6;
+
+    // Items
+    let s_item = PxScale::from(layout.fonts.item);
+    let s_note = PxScale::from(layout.fonts.note);
+    let name_gap = 8; // keep wrapped names off the qty column
+    let note_indent = 24;
+    let note_gap = (layout.row_gap as f32 * layout.fonts.note / layout.fonts.item).round() as i32;
+    let row_rule_gap = layout.row_gap / 4 + layout.separator_thickness as i32;
+    if data.items.is_empty() {
+        metrics.row("no items", y);
+        draw_mixed_rtl_center(img, ts, s_item, EMPTY_ITEMS_MESSAGE, margin_left, right_edge, y);
+        y += layout.row_gap;
+    }
+    for (i, it) in ordered_items(data, layout).into_iter().enumerate() {
+        let (s_name, lines) = match layout.name_overflow {
+            Overflow::Wrap => (s_item, wrap_rtl(ts, s_item, &it.name, w_name - name_gap)),
+            Overflow::Ellipsis => (s_item, vec![ellipsize(ts, s_item, &it.name, w_name - name_gap)]),
+            Overflow::Shrink => {
+                let s = shrink_to_fit(ts, s_item, &it.name, w_name - name_gap);
+                (s, vec![ellipsize(ts, s, &it.name, w_name - name_gap)])
+            }
+        };
+        // a shrunk name keeps the row's baseline
+        let name_dy = (ts.arabic.as_scaled(s_item).ascent() - ts.arabic.as_scaled(s_name).ascent()).round() as i32;
+        let notes: Vec<String> = it.notes.iter()
+            .filter(|n| !n.trim().is_empty())
+            .flat_map(|n| wrap_rtl(ts, s_note, n, w_name - name_gap - note_indent))
+            .collect();
+        let sku = it.sku_line(layout);
+        let small_lines = sku.is_some() as i32 + notes.len() as i32;
+        metrics.row(format!("item {}", i + 1), y);
+        // Zebra: shade odd rows first so the text lands on top
+        if layout.zebra && i % 2 == 1 {
+            let rows = lines.len() as i32 + (it.has_discount() && !kitchen) as i32;
+            let top = y - layout.row_gap / 6;
+            let dot = layout.supersample.clamp(1, 3) as u32;
+            let bottom = top + rows * layout.row_gap + small_lines * note_gap;
+            draw_shade(img, margin_left, top, right_edge, bottom, dot);
+        }
+        if layout.show_item_index {
+            let index = localize_digits(&(i + 1).to_string(), layout.number_locale);
+            let s_index = shrink_to_fit(ts, s_item, &index, w_index - name_gap);
+            let index_dy = (ts.arabic.as_scaled(s_item).ascent() - ts.arabic.as_scaled(s_index).ascent()).round() as i32;
+            draw_ltr_right(img, ts, s_index, &index, right_edge, y + index_dy);
+        }
+        draw_name(img, ts, s_name, it, &lines[0], r_name, y + name_dy);
+        // qty as provided, or per its unit / qty_decimals, shrunk to fit its column;
+        // a label like "كجم" needs bidi
+        let qty = localize_digits(&it.qty_text(layout.qty_decimals, layout.weight_label.as_deref()), layout.number_locale);
+        let s_qty = shrink_to_fit(ts, s_item, &qty, w_qty - name_gap);
+        let qty_dy = (ts.arabic.as_scaled(s_item).ascent() - ts.arabic.as_scaled(s_qty).ascent()).round() as i32;
+        if qty.chars().any(is_rtl_char) {
+            draw_mixed_rtl_right(img, ts, s_qty, &qty, r_qty, y + qty_dy);
+        } else {
+            draw_ltr_right(img, ts, s_qty, &qty, r_qty, y + qty_dy);
+        }
+        if !kitchen {
+            draw_mixed_rtl_right(img, ts, s_item, &price_cell(it.price), r_price, y);
+            draw_mixed_rtl_right(img, ts, s_item, &cell(it.total), r_total, y);
+            if it.has_discount() {
+                strike_right(img, ts, s_item, &cell(it.total), r_total, y);
+            }
+        }
+        if itemized {
+            draw_mixed_rtl_right(img, ts, s_item, &cell(it.tax(data.tax_rate)), r_tax, y);
+        }
+        for line in &lines[1..] {
+            y += layout.row_gap;
+            draw_name(img, ts, s_item, it, line, r_name, y);
+        }
+        // SKU, then notes: smaller lines in the name column, the SKU an LTR run at its
+        // right edge, the notes indented
+        if let Some(sku) = sku {
+            y += layout.row_gap;
+            draw_ltr_right(img, ts, s_note, sku, r_name, y);
+        }
+        for (j, note) in notes.iter().enumerate() {
+            y += if j == 0 && sku.is_none() { layout.row_gap } else { note_gap };
+            draw_mixed_rtl_right(img, ts, s_note, note, r_name - note_indent, y);
+        }
+        if small_lines > 0 {
+            y += note_gap - layout.row_gap; // the row advance below assumes an item line
+        }
+        // Per-item discount: amount under the name, discounted value under the struck total
+        if it.has_discount() && !kitchen {
+            y += layout.row_gap;
+            draw_mixed_rtl_right(img, ts, s_item, &format!("خصم {}", amount(it.discount.unwrap_or(0.0))), r_name, y);
+            draw_mixed_rtl_right(img, ts, s_item, &cell(it.value()), r_total, y);
+        }
+        y += layout.row_gap;
+        // Row separator between items (not after the last: the section separator follows)
+        let every = layout.row_separator_every.max(1) as usize;
+        if let Some(style) = layout.row_separator.filter(|_| (i + 1) % every == 0 && i + 1 < data.items.len()) {
+            y += row_rule_gap;
+            metrics.separators.push(y);
+            draw_separator(img, ts, layout, style, y, margin_left, right_edge);
+            y += row_rule_gap;
+        }
+    }
+
+    // Separator line
+    y += 18;
+    metrics.separators.push(y);
+    draw_separator(img, ts, layout, layout.separator, y, margin_left, right_edge);
+    y += 12;
+
+    if kitchen {
+        return Ok(y + layout.margin_bottom);
+    }
+
+    let has_tax = data.has_tax();
+    let has_discount = data.discount > 0.0001;
+
+    // Subtotal: only shown when a discount or tax needs explaining
+    if has_tax || has_discount {
+        metrics.row("subtotal", y);
+        draw_amount_row(img, ts, layout, "الإجمالي الفرعي", &money(data.subtotal()), right_edge, y);
+        y += layout.row_gap - 6;
+    }
+
+    // Discount (optional)
+    if has_discount {
+        metrics.row("discount", y);
+        draw_amount_row(img, ts, layout, "الخصم", &money(data.discount), right_edge, y);
+        y += layout.row_gap - 6;
+    }
+
+    // VAT (optional); with itemized tax it is the sum of the tax column
+    if has_tax {
+        let label = if itemized { "إجمالي الضريبة" } else { "ضريبة القيمة المضافة" };
+        metrics.row("tax", y);
+        draw_amount_row(img, ts, layout, label, &money(data.tax_amount()), right_edge, y);
+        y += layout.row_gap - 6;
+    }
+
+    // Total (as provided, or computed when VAT applies), in bold
+    let gap = 12;
+    let label = "إجمالي الفاتورة";
+    let lw = measure_bold(ts, PxScale::from(layout.fonts.total_label), label);
+    let right = right_edge;
+
+    metrics.row("total", y);
+    if layout.red_total {
+        let bottom = (y - 10 + layout.fonts.total_value as i32).max(y + layout.fonts.total_label as i32);
+        metrics.red.push((y - 10, bottom));
+    }
+    draw_bold(img, ts, PxScale::from(layout.fonts.total_value),
+              &money(data.grand_total()), right - lw - gap, y - 10);
+    draw_bold(img, ts, PxScale::from(layout.fonts.total_label), label, right, y);
+    y += layout.row_gap;
+
+    // Total in words (optional), wrapped to the inner width
+    if layout.show_total_in_words {
+        let scale = PxScale::from(layout.fonts.footer);
+        let words = amount_to_arabic_words(data.grand_total(), data.currency.as_deref().unwrap_or(""));
+        metrics.row("total_in_words", y);
+        for line in wrap_rtl(ts, scale, &words, inner_w) {
+            draw_mixed_rtl_center(img, ts, scale, &line, margin_left, right_edge, y);
+            y += layout.fonts.footer as i32 + 2;
+        }
+        y += 6;
+    }
+
+    // Payment (optional): method, amount paid, change or outstanding balance
+    if let Some(paid) = data.amount_paid {
+        metrics.row("payment", y);
+        if let Some(method) = data.payment_method.as_deref().filter(|m| !m.is_empty()) {
+            draw_amount_row(img, ts, layout, "طريقة الدفع", method, right_edge, y);
+            y += layout.row_gap - 6;
+        }
+        draw_amount_row(img, ts, layout, "المدفوع", &money(paid), right_edge, y);
+        y += layout.row_gap - 6;
+        let change = paid - data.grand_total();
+        let label = if change < -0.0001 { "المتبقي" } else { "الباقي" };
+        draw_amount_row(img, ts, layout, label, &money(change), right_edge, y);
+        y += layout.row_gap;
+    }
+
+    // Footer: Arabic lines go through bidi, Latin/number lines (phones) stay plain LTR
+    for (i, line) in data.footer_lines.iter().enumerate() {
+        metrics.row(format!("footer {}", i + 1), y);
+        let rtl = line.text.chars().any(is_rtl_char);
+        let size = line.size.unwrap_or(if rtl { layout.fonts.footer } else { layout.fonts.footer_phones });
+        let scale = PxScale::from(size);
+        let w = if rtl { measure_mixed(ts, scale, &line.text) } else { ts.ltr_width(scale, &line.text) };
+        let x = match line.align {
+            Align::Left => margin_left,
+            Align::Center => margin_left + (inner_w - w) / 2,
+            Align::Right => right_edge - w,
+        };
+        if rtl {
+            draw_mixed_rtl_right(img, ts, scale, &line.text, x + w, y);
+        } else {
+            draw_crisp(img, &line.text, x, y, scale, ts.ltr_font(&line.text));
+        }
+        if line.red {
+            metrics.red.push((y, y + size as i32));
+        }
+        y += size as i32 + 2;
+    }
+
+    // QR (optional, unless already in the header), natively or drawn with a 2-module quiet zone above and below
+    if let Some(qr) = qr.filter(|_| !side_by_side) {
+        if data.qr_spec.native {
+            metrics.row("qr", y);
+            splices.push(Splice { row: y.max(0) as u32, bytes: qr_command(&data.qr_spec, qr)? });
+        } else {
+            let quiet = layout.qr_module_size.max(1) as i32 * 2;
+            y += quiet;
+            metrics.row("qr", y);
+            y += draw_qr(img, qr, data.qr_spec.ec, layout.qr_module_size, margin_left, right_edge, y)?;
+            y += quiet;
+        }
+    }
+
+    // Stub (optional): fed past the cutter and partially cut off, then its message and QR
+    if let Some(stub) = &data.stub {
+        y += layout.row_gap / 2;
+        splices.push(Splice { row: y.max(0) as u32, bytes: stub_cut(layout) });
+        metrics.row("stub", y);
+        y += layout.row_gap / 2;
+        let scale = PxScale::from(layout.fonts.footer);
+        for line in &stub.lines {
+            draw_mixed_rtl_center(img, ts, scale, line, margin_left, right_edge, y);
+            y += layout.fonts.footer as i32 + 2;
+        }
+        if let Some(qr) = stub.qr.as_deref().filter(|s| !s.is_empty()) {
+            let quiet = layout.qr_module_size.max(1) as i32 * 2;
+            y += quiet;
+            y += draw_qr(img, qr, QrEc::M, layout.qr_module_size, margin_left, right_edge, y)?;
+            y += quiet;
+        }
+    }
+
+    y += layout.margin_bottom;
+    Ok(y)
+}
+
+// ---------------- Native text mode ----------------
+
+/// True when the receipt can be printed with the printer's own ASCII font:
+/// every printed string (layout labels included) is ASCII and there is nothing that
+/// needs a raster (logo, QR, bitmap barcode).
+fn text_mode_supported(data: &ReceiptData, layout: &Layout) -> bool {
+    let date_time = data.date_time();
+    let texts = [data.title(), date_time.as_ref(), data.invoice_no.as_str()];
+    texts.iter().all(|s| s.is_ascii())
+        && data.store.is_none() // its labels are Arabic
+        && data.footer_lines.iter().all(|l| l.text.is_ascii())
+        && data.items.iter().all(|i| i.name.is_ascii() && i.qty_str.is_ascii() && i.notes.iter().all(|n| n.is_ascii())
+                                  && i.sku.as_deref().is_none_or(str::is_ascii))
+        && data.payment_method.as_deref().is_none_or(str::is_ascii)
+        && data.watermark.as_deref().is_none_or(str::is_ascii)
+        && data.currency.as_deref().is_none_or(str::is_ascii)
+        && data.logo_path.as_deref().is_none_or(str::is_empty)
+        && (data.qr_spec.native || data.qr.as_deref().is_none_or(str::is_empty))
+        && data.stub.as_ref().is_none_or(|s| s.lines.iter().all(|l| l.is_ascii()) && s.qr.as_deref().is_none_or(str::is_empty))
+        && data.barcode.as_ref().is_none_or(|b| matches!(b.render, BarcodeRenderMode::Native))
+        && layout.invoice_label.as_deref().is_none_or(str::is_ascii)
+        && layout.weight_label.as_deref().is_none_or(str::is_ascii)
+}
+
+/// Greedy word wrap to `max` chars; words longer than a line are split.
+fn wrap_chars(s: &str, max: usize) -> Vec<String> {
+    let max = max.max(1);
+    let len = |s: &str| s.chars().count();
+    let mut lines = Vec::new();
+    let mut cur = String::new();
+    for word in s.split_whitespace() {
+        let mut word = word;
+        while len(word) > max {
+            if !cur.is_empty() { lines.push(std::mem::take(&mut cur)); }
+            let cut = word.char_indices().nth(max).map_or(word.len(), |(i, _)| i);
+            lines.push(word[..cut].to_string());
+            word = &word[cut..];
+        }
+        if cur.is_empty() {
+            cur = word.to_string();
+        } else if len(&cur) + 1 + len(word) <= max {
+            cur.push(' ');
+            cur.push_str(word);
+        } else {
+            lines.push(std::mem::replace(&mut cur, word.to_string()));
+        }
+    }
+    if !cur.is_empty() || lines.is_empty() { lines.push(cur); }
+    lines
+}
+
+/// "label .... value" padded to `width` chars.
+fn text_amount_row(label: &str, value: &str, width: usize) -> String {
+    let pad = width.saturating_sub(label.len() + value.len()).max(1);
+    format!("{}{}{}", label, " ".repeat(pad), value)
+}
+
+/// ESC/POS text commands for an ASCII receipt (Font A: 12 dots per character).
+/// Mirrors the bitmap layout but left-to-right: name, qty, price, total.
+fn render_text_mode(data: &ReceiptData, layout: &Layout) -> Result<Vec<u8>, String> {
+    check_items(data, layout)?;
+    const ALIGN_LEFT: [u8; 3] = [0x1B, 0x61, 0x00];
+    const ALIGN_CENTER: [u8; 3] = [0x1B, 0x61, 0x01];
+    const BOLD_ON: [u8; 3] = [0x1B, 0x45, 0x01];
+    const BOLD_OFF: [u8; 3] = [0x1B, 0x45, 0x00];
+    let size = |n: u8| [0x1D, 0x21, n]; // GS ! : high nibble width, low nibble height multiplier
+
+    let width = (layout.paper_width_px / 12).max(16) as usize;
+    // ASCII only: the locale's digits would not survive the code page, grouping does
+    let amount_dp = |v: f32, dp: u8| format_amount(v, dp, NumberLocale::Western, layout.thousands_separator);
+    let amount = |v: f32| amount_dp(v, layout.total_decimals);
+    let with_code = |a: String| match data.currency.as_deref().map(str::trim).filter(|c| !c.is_empty()) {
+        None => a,
+        Some(c) => match layout.currency_position {
+            CurrencyPosition::After => format!("{} {}", a, c),
+            CurrencyPosition::Before => format!("{} {}", c, a),
+        },
+    };
+    let money = |v: f32| with_code(amount(v));
+    let cell = |v: f32| if layout.currency_on_prices { money(v) } else { amount(v) };
+    let price_cell = |v: f32| {
+        let a = amount_dp(v, layout.price_decimals);
+        if layout.currency_on_prices { with_code(a) } else { a }
+    };
+    let mut out = Vec::new();
+    let line = |out: &mut Vec<u8>, s: &str| { out.extend_from_slice(s.as_bytes()); out.push(b'\n'); };
+
+    // Header
+    out.extend_from_slice(&ALIGN_CENTER);
+    out.extend_from_slice(&BOLD_ON);
+    out.extend_from_slice(&size(0x11));
+    if let Some(mark) = data.watermark() { line(&mut out, mark); }
+    for l in data.title().lines() { line(&mut out, l); }
+    out.extend_from_slice(&size(0x00));
+    out.extend_from_slice(&BOLD_OFF);
+    let date_time = data.date_time();
+    if !date_time.is_empty() { line(&mut out, &date_time); }
+    if !data.invoice_no.is_empty() { line(&mut out, &data.invoice_line(layout)); }
+    if let Some(spec) = &data.barcode {
+        out.extend(barcode_command(spec, &data.invoice_no)?);
+        out.push(b'\n');
+    }
+    out.extend_from_slice(&ALIGN_LEFT);
+
+    // Columns: the bitmap's positions mirrored left to right (position 0 leftmost),
+    // same fractions; the name takes the rounding slack
+    let mut widths: [usize; 4] = std::array::from_fn(|i| (width as f32 * layout.cols[i]) as usize);
+    let name_pos = layout.col_pos(ColumnKind::Name);
+    widths[name_pos] = 0;
+    widths[name_pos] = width.saturating_sub(widths.iter().sum()).max(1);
+    let w_name = widths[name_pos];
+    let row = |name: &str, qty: &str, price: &str, total: &str| {
+        let mut s = String::new();
+        for (kind, w) in layout.col_order.iter().zip(widths) {
+            match kind {
+                ColumnKind::Name => s.push_str(&format!("{:<w$}", name, w = w)),
+                ColumnKind::Qty => s.push_str(&format!("{:>w$}", qty, w = w)),
+                ColumnKind::Price => s.push_str(&format!("{:>w$}", price, w = w)),
+                ColumnKind::Total => s.push_str(&format!("{:>w$}", total, w = w)),
+            }
+        }
+        s
+    };
+    out.extend_from_slice(&BOLD_ON);
+    line(&mut out, &row("Item", "Qty", "Price", "Total"));
+    out.extend_from_slice(&BOLD_OFF);
+    if data.items.is_empty() {
+        line(&mut out, &row("No items", "", "", ""));
+    }
+    for it in ordered_items(data, layout) {
+        let names = match layout.name_overflow {
+            Overflow::Wrap => wrap_chars(&it.name, w_name.saturating_sub(1)),
+            Overflow::Ellipsis | Overflow::Shrink => vec![clip_chars(&it.name, w_name.saturating_sub(1), "...")],
+        };
+        let qty = it.qty_text(layout.qty_decimals, layout.weight_label.as_deref());
+        line(&mut out, &row(&names[0], &qty, &price_cell(it.price), &cell(it.total)));
+        for n in &names[1..] { line(&mut out, &row(n, "", "", "")); }
+        if let Some(sku) = it.sku_line(layout) { line(&mut out, &row(&format!("  {}", sku), "", "", "")); }
+        for note in it.notes.iter().filter(|n| !n.trim().is_empty()) {
+            for n in wrap_chars(note, w_name.saturating_sub(3)) { line(&mut out, &row(&format!("  {}", n), "", "", "")); }
+        }
+        if it.has_discount() {
+            line(&mut out, &row(&format!(" discount {}", amount(it.discount.unwrap_or(0.0))), "", "", &cell(it.value())));
+        }
+    }
+    line(&mut out, &"-".repeat(width));
+
+    // Totals
+    let has_tax = data.has_tax();
+    let has_discount = data.discount > 0.0001;
+    if has_tax || has_discount { line(&mut out, &text_amount_row("Subtotal", &money(data.subtotal()), width)); }
+    if has_discount { line(&mut out, &text_amount_row("Discount", &money(data.discount), width)); }
+    if has_tax { line(&mut out, &text_amount_row("VAT", &money(data.tax_amount()), width)); }
+    out.extend_from_slice(&BOLD_ON);
+    out.extend_from_slice(&size(0x01));
+    line(&mut out, &text_amount_row("TOTAL", &money(data.grand_total()), width));
+    out.extend_from_slice(&size(0x00));
+    out.extend_from_slice(&BOLD_OFF);
+    if let Some(paid) = data.amount_paid {
+        if let Some(method) = data.payment_method.as_deref().filter(|m| !m.is_empty()) {
+            line(&mut out, &text_amount_row("Payment", method, width));
+        }
+        line(&mut out, &text_amount_row("Paid", &money(paid), width));
+        let change = paid - data.grand_total();
+        line(&mut out, &text_amount_row(if change < -0.0001 { "Due" } else { "Change" }, &money(change), width));
+    }
+
+    // Footer
+    for f in data.footer_lines.iter().filter(|l| !l.text.is_empty()) {
+        let n = match f.align { Align::Left => 0, Align::Center => 1, Align::Right => 2 };
+        out.extend_from_slice(&[0x1B, 0x61, n]);
+        line(&mut out, &f.text);
+    }
+    if let Some(qr) = data.qr.as_deref().filter(|q| !q.is_empty() && data.qr_spec.native) {
+        out.extend(qr_command(&data.qr_spec, qr)?);
+    }
+
+    // Stub
+    if let Some(stub) = &data.stub {
+        out.extend(stub_cut(layout));
+        out.extend_from_slice(&ALIGN_CENTER);
+        for l in &stub.lines { line(&mut out, l); }
+    }
+    out.extend_from_slice(&ALIGN_LEFT);
+    Ok(out)
+}
+
+// ---------------- Code page 864 mode ----------------
+
+/// Unicode → IBM code page 864 (Arabic), sorted by char. Contextual forms the code
+/// page lacks map to the nearest one it has (final → isolated, medial → initial),
+/// bare letters to their isolated form.
+const CP864: [(char, u8); 213] = [
+    ('\u{00A0}', 0xA0), ('\u{00A2}', 0xC0), ('\u{00A3}', 0xA3), ('\u{00A4}', 0xA4),
+    ('\u{00A6}', 0xDB), ('\u{00AB}', 0x97), ('\u{00AC}', 0xDC), ('\u{00AD}', 0xA1),
+    ('\u{00B0}', 0x80), ('\u{00B1}', 0x93), ('\u{00B7}', 0x81), ('\u{00BB}', 0x98),
+    ('\u{00BC}', 0x95), ('\u{00BD}', 0x94), ('\u{00D7}', 0xDE), ('\u{00F7}', 0xDD),
+    ('\u{03B2}', 0x90), ('\u{03C6}', 0x92), ('\u{060C}', 0xAC), ('\u{061B}', 0xBB),
+    ('\u{061F}', 0xBF), ('\u{0621}', 0xC1), ('\u{0622}', 0xC2), ('\u{0623}', 0xC3),
+    ('\u{0624}', 0xC4), ('\u{0625}', 0xC7), ('\u{0626}', 0xC6), ('\u{0627}', 0xC7),
+    ('\u{0628}', 0xA9), ('\u{0629}', 0xC9), ('\u{062A}', 0xAA), ('\u{062B}', 0xAB),
+    ('\u{062C}', 0xAD), ('\u{062D}', 0xAE), ('\u{062E}', 0xAF), ('\u{062F}', 0xCF),
+    ('\u{0630}', 0xD0), ('\u{0631}', 0xD1), ('\u{0632}', 0xD2), ('\u{0633}', 0xBC),
+    ('\u{0634}', 0xBD), ('\u{0635}', 0xBE), ('\u{0636}', 0xEB), ('\u{0637}', 0xD7),
+    ('\u{0638}', 0xD8), ('\u{0639}', 0xDF), ('\u{063A}', 0xEE), ('\u{0640}', 0xE0),
+    ('\u{0641}', 0xBA), ('\u{0642}', 0xF8), ('\u{0643}', 0xFC), ('\u{0644}', 0xFB),
+    ('\u{0645}', 0xEF), ('\u{0646}', 0xF2), ('\u{0647}', 0xF3), ('\u{0648}', 0xE8),
+    ('\u{0649}', 0xE9), ('\u{064A}', 0xFD), ('\u{0651}', 0xF1), ('\u{0660}', 0xB0),
+    ('\u{0661}', 0xB1), ('\u{0662}', 0xB2), ('\u{0663}', 0xB3), ('\u{0664}', 0xB4),
+    ('\u{0665}', 0xB5), ('\u{0666}', 0xB6), ('\u{0667}', 0xB7), ('\u{0668}', 0xB8),
+    ('\u{0669}', 0xB9), ('\u{066A}', 0x25), ('\u{2219}', 0x82), ('\u{221A}', 0x83),
+    ('\u{221E}', 0x91), ('\u{2248}', 0x96), ('\u{2500}', 0x85), ('\u{2502}', 0x86),
+    ('\u{250C}', 0x8D), ('\u{2510}', 0x8C), ('\u{2514}', 0x8E), ('\u{2518}', 0x8F),
+    ('\u{251C}', 0x8A), ('\u{2524}', 0x88), ('\u{252C}', 0x89), ('\u{2534}', 0x8B),
+    ('\u{253C}', 0x87), ('\u{2592}', 0x84), ('\u{25A0}', 0xFE), ('\u{FE7D}', 0xF0),
+    ('\u{FE80}', 0xC1), ('\u{FE81}', 0xC2), ('\u{FE82}', 0xA2), ('\u{FE83}', 0xC3),
+    ('\u{FE84}', 0xA5), ('\u{FE85}', 0xC4), ('\u{FE86}', 0xC4), ('\u{FE87}', 0xC7),
+    ('\u{FE88}', 0xA8), ('\u{FE89}', 0xC6), ('\u{FE8A}', 0xC6), ('\u{FE8B}', 0xC6),
+    ('\u{FE8C}', 0xC6), ('\u{FE8D}', 0xC7), ('\u{FE8E}', 0xA8), ('\u{FE8F}', 0xA9),
+    ('\u{FE90}', 0xA9), ('\u{FE91}', 0xC8), ('\u{FE92}', 0xC8), ('\u{FE93}', 0xC9),
+    ('\u{FE94}', 0xC9), ('\u{FE95}', 0xAA), ('\u{FE96}', 0xAA), ('\u{FE97}', 0xCA),
+    ('\u{FE98}', 0xCA), ('\u{FE99}', 0xAB), ('\u{FE9A}', 0xAB), ('\u{FE9B}', 0xCB),
+    ('\u{FE9C}', 0xCB), ('\u{FE9D}', 0xAD), ('\u{FE9E}', 0xAD), ('\u{FE9F}', 0xCC),
+    ('\u{FEA0}', 0xCC), ('\u{FEA1}', 0xAE), ('\u{FEA2}', 0xAE), ('\u{FEA3}', 0xCD),
+    ('\u{FEA4}', 0xCD), ('\u{FEA5}', 0xAF), ('\u{FEA6}', 0xAF), ('\u{FEA7}', 0xCE),
+    ('\u{FEA8}', 0xCE), ('\u{FEA9}', 0xCF), ('\u{FEAA}', 0xCF), ('\u{FEAB}', 0xD0),
+    ('\u{FEAC}', 0xD0), ('\u{FEAD}', 0xD1), ('\u{FEAE}', 0xD1), ('\u{FEAF}', 0xD2),
+    ('\u{FEB0}', 0xD2), ('\u{FEB1}', 0xBC), ('\u{FEB2}', 0xBC), ('\u{FEB3}', 0xD3),
+    ('\u{FEB4}', 0xD3), ('\u{FEB5}', 0xBD), ('\u{FEB6}', 0xBD), ('\u{FEB7}', 0xD4),
+    ('\u{FEB8}', 0xD4), ('\u{FEB9}', 0xBE), ('\u{FEBA}', 0xBE), ('\u{FEBB}', 0xD5),
+    ('\u{FEBC}', 0xD5), ('\u{FEBD}', 0xEB), ('\u{FEBE}', 0xEB), ('\u{FEBF}', 0xD6),
+    ('\u{FEC0}', 0xD6), ('\u{FEC1}', 0xD7), ('\u{FEC2}', 0xD7), ('\u{FEC3}', 0xD7),
+    ('\u{FEC4}', 0xD7), ('\u{FEC5}', 0xD8), ('\u{FEC6}', 0xD8), ('\u{FEC7}', 0xD8),
+    ('\u{FEC8}', 0xD8), ('\u{FEC9}', 0xDF), ('\u{FECA}', 0xC5), ('\u{FECB}', 0xD9),
+    ('\u{FECC}', 0xEC), ('\u{FECD}', 0xEE), ('\u{FECE}', 0xED), ('\u{FECF}', 0xDA),
+    ('\u{FED0}', 0xF7), ('\u{FED1}', 0xBA), ('\u{FED2}', 0xBA), ('\u{FED3}', 0xE1),
+    ('\u{FED4}', 0xE1), ('\u{FED5}', 0xF8), ('\u{FED6}', 0xF8), ('\u{FED7}', 0xE2),
+    ('\u{FED8}', 0xE2), ('\u{FED9}', 0xFC), ('\u{FEDA}', 0xFC), ('\u{FEDB}', 0xE3),
+    ('\u{FEDC}', 0xE3), ('\u{FEDD}', 0xFB), ('\u{FEDE}', 0xFB), ('\u{FEDF}', 0xE4),
+    ('\u{FEE0}', 0xE4), ('\u{FEE1}', 0xEF), ('\u{FEE2}', 0xEF), ('\u{FEE3}', 0xE5),
+    ('\u{FEE4}', 0xE5), ('\u{FEE5}', 0xF2), ('\u{FEE6}', 0xF2), ('\u{FEE7}', 0xE6),
+    ('\u{FEE8}', 0xE6), ('\u{FEE9}', 0xF3), ('\u{FEEA}', 0xF3), ('\u{FEEB}', 0xE7),
+    ('\u{FEEC}', 0xF4), ('\u{FEED}', 0xE8), ('\u{FEEE}', 0xE8), ('\u{FEEF}', 0xE9),
+    ('\u{FEF0}', 0xF5), ('\u{FEF1}', 0xFD), ('\u{FEF2}', 0xF6), ('\u{FEF3}', 0xEA),
+    ('\u{FEF4}', 0xEA), ('\u{FEF5}', 0xF9), ('\u{FEF6}', 0xFA), ('\u{FEF7}', 0x99),
+    ('\u{FEF8}', 0x9A), ('\u{FEF9}', 0x9D), ('\u{FEFA}', 0x9E), ('\u{FEFB}', 0x9D),
+    ('\u{FEFC}', 0x9E),
+];
+
+fn cp864_byte(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8, // 0x25 is the Arabic percent sign, near enough for '%'
+        '\u{066B}' => b'.',
+        '\u{066C}' => b',',
+        _ => CP864.binary_search_by_key(&c, |e| e.0).map_or(b'?', |i| CP864[i].1),
+    }
+}
+
+/// One logical line as CP864 bytes in visual (left-to-right) order: reshaped into
+/// presentation forms, RTL runs reversed and mirrored; bidi marks and harakat
+/// other than shadda are dropped.
+fn cp864_line(reshaper: &ArabicReshaper, logical: &str) -> Vec<u8> {
+    let shaped = reshaper.reshape(logical);
+    let info = BidiInfo::new(&shaped, Some(Level::rtl()));
+    let keep = |c: &char| !is_bidi_mark(*c) && !matches!(*c, '\u{064B}'..='\u{0650}' | '\u{0652}'..='\u{065F}');
+    let mut out = Vec::new();
+    for para in &info.paragraphs {
+        let (levels, runs) = info.visual_runs(para, para.range.clone());
+        for run in runs {
+            let text = shaped[run.clone()].chars().filter(keep);
+            if levels[run.start].is_rtl() {
+                out.extend(text.rev().map(mirror_char).map(cp864_byte));
+            } else {
+                out.extend(text.map(cp864_byte));
+            }
+        }
+    }
+    out
+}
+
+/// Right-aligned in `w` cells; an over-long RTL cell keeps its (logical) start.
+fn cp864_cell(bytes: Vec<u8>, w: usize) -> Vec<u8> {
+    if bytes.len() >= w {
+        return bytes[bytes.len() - w..].to_vec();
+    }
+    let mut cell = vec![b' '; w - bytes.len()];
+    cell.extend(bytes);
+    cell
+}
+
+/// ESC/POS text commands for printers with no raster support, in their built-in
+/// Arabic code page (`ESC t codepage_id`). Laid out like the bitmap, right to left;
+/// the logo and QR need graphics and are left out.
+fn render_codepage(data: &ReceiptData, layout: &Layout) -> Result<Vec<u8>, String> {
+    check_items(data, layout)?;
+    const ALIGN_LEFT: [u8; 3] = [0x1B, 0x61, 0x00];
+    const ALIGN_CENTER: [u8; 3] = [0x1B, 0x61, 0x01];
+    const BOLD_ON: [u8; 3] = [0x1B, 0x45, 0x01];
+    const BOLD_OFF: [u8; 3] = [0x1B, 0x45, 0x00];
+    let size = |n: u8| [0x1D, 0x21, n];
+
+    let reshaper = layout.reshape.reshaper();
+    let enc = |s: &str| cp864_line(&reshaper, s);
+    let width = (layout.paper_width_px / 12).max(16) as usize;
+    let amount_dp = |v: f32, dp: u8| format_amount(v, dp, layout.number_locale, layout.thousands_separator);
+    let amount = |v: f32| amount_dp(v, layout.total_decimals);
+    let money = |v: f32| with_currency(amount(v), data.currency.as_deref(), layout.currency_position);
+    let cell = |v: f32| if layout.currency_on_prices { money(v) } else { amount(v) };
+    let price_cell = |v: f32| {
+        let a = amount_dp(v, layout.price_decimals);
+        if layout.currency_on_prices { with_currency(a, data.currency.as_deref(), layout.currency_position) } else { a }
+    };
+    // value on the left, label on the right
+    let amount_row = |label: &str, value: &str| {
+        let (l, mut row) = (enc(label), enc(value));
+        let pad = width.saturating_sub(l.len() + row.len()).max(1);
+        row.resize(row.len() + pad, b' ');
+        row.extend(l);
+        row
+    };
+    let mut out = vec![0x1B, 0x74, layout.codepage_id];
+    let line = |out: &mut Vec<u8>, bytes: Vec<u8>| { out.extend(bytes); out.push(b'\n'); };
+
+    // Header
+    out.extend_from_slice(&ALIGN_CENTER);
+    out.extend_from_slice(&BOLD_ON);
+    out.extend_from_slice(&size(0x11));
+    if let Some(mark) = data.watermark() { line(&mut out, enc(mark)); }
+    for l in data.title().lines() { line(&mut out, enc(l)); }
+    out.extend_from_slice(&size(0x00));
+    out.extend_from_slice(&BOLD_OFF);
+    for l in data.store.iter().flat_map(StoreInfo::lines) { line(&mut out, enc(&l)); }
+    let date_time = data.date_time();
+    if !date_time.is_empty() { line(&mut out, enc(&date_time)); }
+    if !data.invoice_no.is_empty() { line(&mut out, enc(&data.invoice_line(layout))); }
+    if let Some(spec) = &data.barcode {
+        out.extend(barcode_command(spec, &data.invoice_no)?);
+        out.push(b'\n');
+    }
+    out.extend_from_slice(&ALIGN_LEFT);
+
+    // Columns at the layout's fractions, position 0 rightmost; the name takes the slack
+    let mut widths: [usize; 4] = std::array::from_fn(|i| (width as f32 * layout.cols[i]) as usize);
+    let name_pos = layout.col_pos(ColumnKind::Name);
+    widths[name_pos] = 0;
+    widths[name_pos] = width.saturating_sub(widths.iter().sum()).max(1);
+    let w_name = widths[name_pos];
+    let row = |name: &str, qty: &str, price: &str, total: &str| {
+        let mut r = Vec::with_capacity(width);
+        for (kind, w) in layout.col_order.iter().zip(widths).rev() {
+            let text = match kind {
+                ColumnKind::Name => name,
+                ColumnKind::Qty => qty,
+                ColumnKind::Price => price,
+                ColumnKind::Total => total,
+            };
+            r.extend(cp864_cell(enc(text), w));
+        }
+        r
+    };
+    out.extend_from_slice(&BOLD_ON);
+    line(&mut out, row("الصنف", "الكمية", "السعر", "القيمة"));
+    out.extend_from_slice(&BOLD_OFF);
+    if data.items.is_empty() {
+        line(&mut out, row(EMPTY_ITEMS_MESSAGE, "", "", ""));
+    }
+    for it in ordered_items(data, layout) {
+        let names = match layout.name_overflow {
+            Overflow::Wrap => wrap_chars(&it.name, w_name.saturating_sub(1)),
+            Overflow::Ellipsis | Overflow::Shrink => vec![clip_chars(&it.name, w_name.saturating_sub(1), "..")],
+        };
+        let qty = localize_digits(&it.qty_text(layout.qty_decimals, layout.weight_label.as_deref()), layout.number_locale);
+        line(&mut out, row(&names[0], &qty, &price_cell(it.price), &cell(it.total)));
+        for n in &names[1..] { line(&mut out, row(n, "", "", "")); }
+        if let Some(sku) = it.sku_line(layout) { line(&mut out, row(&format!("  {}", sku), "", "", "")); }
+        for note in it.notes.iter().filter(|n| !n.trim().is_empty()) {
+            for n in wrap_chars(note, w_name.saturating_sub(3)) { line(&mut out, row(&format!("  {}", n), "", "", "")); }
+        }
+        if it.has_discount() {
+            line(&mut out, row(&format!("خصم {}", amount(it.discount.unwrap_or(0.0))), "", "", &cell(it.value())));
+        }
+    }
+    line(&mut out, vec![b'-'; width]);
+
+    // Totals
+    let has_tax = data.has_tax();
+    let has_discount = data.discount > 0.0001;
+    if has_tax || has_discount { line(&mut out, amount_row("الإجمالي الفرعي", &money(data.subtotal()))); }
+    if has_discount { line(&mut out, amount_row("الخصم", &money(data.discount))); }
+    if has_tax { line(&mut out, amount_row("ضريبة القيمة المضافة", &money(data.tax_amount()))); }
+    out.extend_from_slice(&BOLD_ON);
+    out.extend_from_slice(&size(0x01));
+    line(&mut out, amount_row("إجمالي الفاتورة", &money(data.grand_total())));
+    out.extend_from_slice(&size(0x00));
+    out.extend_from_slice(&BOLD_OFF);
+    if let Some(paid) = data.amount_paid {
+        if let Some(method) = data.payment_method.as_deref().filter(|m| !m.is_empty()) {
+            line(&mut out, amount_row("طريقة الدفع", method));
+        }
+        line(&mut out, amount_row("المدفوع", &money(paid)));
+        let change = paid - data.grand_total();
+        line(&mut out, amount_row(if change < -0.0001 { "المتبقي" } else { "الباقي" }, &money(change)));
+    }
+
+    // Footer
+    for f in data.footer_lines.iter().filter(|l| !l.text.is_empty()) {
+        let n = match f.align { Align::Left => 0, Align::Center => 1, Align::Right => 2 };
+        out.extend_from_slice(&[0x1B, 0x61, n]);
+        line(&mut out, enc(&f.text));
+    }
+    if let Some(qr) = data.qr.as_deref().filter(|q| !q.is_empty() && data.qr_spec.native) {
+        out.extend(qr_command(&data.qr_spec, qr)?);
+    }
+
+    // Stub (its QR needs a bitmap mode)
+    if let Some(stub) = &data.stub {
+        out.extend(stub_cut(layout));
+        out.extend_from_slice(&ALIGN_CENTER);
+        for l in &stub.lines { line(&mut out, enc(l)); }
+    }
+    out.extend_from_slice(&ALIGN_LEFT);
+    Ok(out)
+}
+
+// ---------------- Calibration page ----------------
+
+/// Test page for dialing in `paper_width_px` and `threshold`: a pixel ruler across
+/// the full width, thresholded and dithered gray ramps, and text at several sizes.
+fn render_calibration(layout: &Layout) -> Result<GrayImage, String> {
+    let assets = Assets::load(None, layout)?;
+    let ts = assets.typesetter(layout)?;
+    let w = layout.paper_width_px;
+    let black = Rgb([0, 0, 0]);
+    let small = PxScale::from(22.0);
+    let mut img: RgbImage = ImageBuffer::from_pixel(w, 620, Rgb([255, 255, 255]));
+
+    // Ruler: a tick every 10 px, longer every 50, labelled every 100; the last pixel
+    // column is marked so a clipped right edge is obvious
+    draw_filled_rect_mut(&mut img, Rect::at(0, 0).of_size(w, 2), black);
+    for x in (0..w).step_by(10) {
+        let len = if x % 100 == 0 { 30 } else if x % 50 == 0 { 20 } else { 10 };
+        draw_filled_rect_mut(&mut img, Rect::at(x as i32, 0).of_size(1, len), black);
+        if x % 100 == 0 && x > 0 {
+            draw_crisp(&mut img, &x.to_string(), x as i32 + 3, 8, small, &ts.latin);
+        }
+    }
+    draw_filled_rect_mut(&mut img, Rect::at(w as i32 - 2, 0).of_size(2, 40), black);
+    let mut y = 50;
+
+    // 16-step ramp, labelled; the printer blacks out every step <= threshold
+    let steps = 16u32;
+    let step_w = w / steps;
+    for i in 0..steps {
+        let v = (i * 255 / (steps - 1)) as u8;
+        draw_filled_rect_mut(&mut img, Rect::at((i * step_w) as i32, y).of_size(step_w, 60), Rgb([v, v, v]));
+        let label = v.to_string();
+        let lw = ts.ltr_width(small, &label);
+        draw_crisp(&mut img, &label, (i * step_w) as i32 + (step_w as i32 - lw) / 2, y + 64, small, &ts.latin);
+    }
+    y += 100;
+
+    // Smooth ramp, dithered the way `dither` treats logos
+    let ramp = GrayImage::from_fn(w, 60, |x, _| Luma([(x * 255 / w.max(2).saturating_sub(1)) as u8]));
+    draw_gray(&mut img, &dither_floyd_steinberg(&ramp, layout.threshold), 0, y);
+    y += 80;
+
+    // Text samples, right-aligned like the receipt
+    for size in [24.0, 32.0, 44.0, 56.0, 72.0] {
+        let scale = PxScale::from(size);
+        draw_mixed_rtl_right(&mut img, &ts, scale, &format!("نص تجريبي 0123 - {}px", size), w as i32 - layout.margins().1, y);
+        y += size as i32 + 8;
+    }
+    let info = format!("width {} px, threshold {}", w, layout.threshold);
+    draw_crisp(&mut img, &info, layout.margins().0, y, small, &ts.latin);
+    y += 30;
+
+    let gray = image::DynamicImage::ImageRgb8(img).to_luma8();
+    Ok(image::imageops::crop_imm(&gray, 0, 0, w, y.max(1) as u32).to_image())
+}
+
+// Pack ESC * 24-dot bands (m=33)
+fn pack_esc_star_24(gray: &GrayImage, y0: u32, threshold: u8) -> Vec<u8> {
+    let w = gray.width();
+    let h = gray.height();
+    let mut band = Vec::with_capacity((w * 3) as usize);
+    for x in 0..w {
+        for byte in 0..3 {
+            let mut b = 0u8;
+            for bit in 0..8 {
+                let yy = y0 + (byte * 8 + bit) as u32;
+                if yy < h {
+                    let Luma([pix]) = *gray.get_pixel(x, yy);
+                    if pix <= threshold { b |= 1 << (7 - bit); }
+                }
+            }
+            band.push(b);
+        }
+    }
+    band
+}
+
+// Pack the whole bitmap as one GS v 0 raster command (m=0, normal density)
+fn pack_gs_v0(gray: &GrayImage, threshold: u8) -> Vec<u8> {
+    let w = gray.width();
+    let h = gray.height();
+    let x_bytes = w.div_ceil(8);
+    let mut out = Vec::with_capacity(8 + (x_bytes * h) as usize);
+    out.extend_from_slice(&[
+        0x1D, 0x76, 0x30, 0x00,
+        (x_bytes & 0xFF) as u8, ((x_bytes >> 8) & 0xFF) as u8,
+        (h & 0xFF) as u8, ((h >> 8) & 0xFF) as u8,
+    ]);
+    pack_rows(gray, 0..h, threshold, &mut out);
+    out
+}
+
+/// Rows per `GS ( L` graphic, so each write stays small on a slow serial link.
+const GRAPHICS_STRIP_ROWS: u32 = 256;
+
+/// `GS ( L` fn 112 storing rows `rows` as a 1-color graphic (`GS 8 L` when it needs
+/// more than 64 KB), then fn 50 printing it.
+fn pack_graphic(gray: &GrayImage, rows: std::ops::Range<u32>, threshold: u8) -> Vec<u8> {
+    let (w, h) = (gray.width(), rows.len() as u32);
+    let p = 10 + (w.div_ceil(8) * h) as usize; // m fn a bx by c xL xH yL yH, then the data
+    let mut out = if p <= 0xFFFF {
+        vec![0x1D, 0x28, 0x4C, (p & 0xFF) as u8, (p >> 8) as u8]
+    } else {
+        [&[0x1D, 0x38, 0x4C][..], &(p as u32).to_le_bytes()].concat()
+    };
+    out.extend_from_slice(&[
+        0x30, 0x70, 0x30, 0x01, 0x01, 0x31,
+        (w & 0xFF) as u8, (w >> 8) as u8, (h & 0xFF) as u8, (h >> 8) as u8,
+    ]);
+    pack_rows(gray, rows, threshold, &mut out);
+    out.extend_from_slice(&[0x1D, 0x28, 0x4C, 0x02, 0x00, 0x30, 0x32]);
+    out
+}
+
+/// The bitmap as `GS ( L` graphics of at most `GRAPHICS_STRIP_ROWS` rows each.
+fn graphics_chunks(gray: &GrayImage, threshold: u8) -> Vec<Vec<u8>> {
+    (0..gray.height())
+        .step_by(GRAPHICS_STRIP_ROWS as usize)
+        .map(|y0| pack_graphic(gray, y0..(y0 + GRAPHICS_STRIP_ROWS).min(gray.height()), threshold))
+        .collect()
+}
+
+/// Rows of the bitmap, 1 bit per dot, MSB leftmost, each row padded to whole bytes.
+fn pack_rows(gray: &GrayImage, rows: std::ops::Range<u32>, threshold: u8, out: &mut Vec<u8>) {
+    let w = gray.width();
+    let x_bytes = w.div_ceil(8);
+    for y in rows {
+        for xb in 0..x_bytes {
+            let mut b = 0u8;
+            for bit in 0..8 {
+                let x = xb * 8 + bit;
+                if x < w {
+                    let Luma([pix]) = *gray.get_pixel(x, y);
+                    if pix <= threshold { b |= 1 << (7 - bit); }
+                }
+            }
+            out.push(b);
+        }
+    }
+}
+
+// ---------------- Driver ----------------
+
+/// Type-erased escpos driver so every transport shares the same band loop.
+struct BoxedDriver(Box<dyn Driver>);
+
+impl Driver for BoxedDriver {
+    fn name(&self) -> String { self.0.name() }
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> { self.0.write(data) }
+    fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> { self.0.read(buf) }
+    fn flush(&self) -> escpos::errors::Result<()> { self.0.flush() }
+}
+
+impl Target {
+    /// Human-readable name ("COM7", "192.168.1.50:9100", "USB 04b8:0202").
+    fn label(&self) -> String {
+        match self {
+            Target::Serial { port, .. } => normalize_com_port(port),
+            Target::Network { host, port } => format!("{}:{}", host, port),
+            Target::Usb { vid, pid } => format!("USB {:04x}:{:04x}", vid, pid),
+        }
+    }
+}
+
+/// Serial port opened through `serialport` directly, so framing and flow control
+/// can be set (the escpos serial driver only takes a baud rate).
+struct SerialDriver {
+    name: String,
+    port: std::cell::RefCell<Box<dyn serialport::SerialPort>>,
+}
+
+impl SerialDriver {
+    fn open(path: &str, baud: u32, s: &SerialSettings, timeout: Duration) -> Result<Self, String> {
+        use serialport::{DataBits, FlowControl, Parity, StopBits};
+        let data_bits = match s.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            n => return Err(format!("unsupported data_bits {} (expected 5-8)", n)),
+        };
+        let stop_bits = match s.stop_bits {
+            1 => StopBits::One,
+            2 => StopBits::Two,
+            n => return Err(format!("unsupported stop_bits {} (expected 1 or 2)", n)),
+        };
+        let parity = match s.parity {
+            SerialParity::None => Parity::None,
+            SerialParity::Odd => Parity::Odd,
+            SerialParity::Even => Parity::Even,
+        };
+        let flow_control = match s.flow_control {
+            SerialFlow::None => FlowControl::None,
+            SerialFlow::Software => FlowControl::Software,
+            SerialFlow::Hardware => FlowControl::Hardware,
+        };
+        let port = serialport::new(path, baud)
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .flow_control(flow_control)
+            .timeout(timeout)
+            .open()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { name: path.to_string(), port: std::cell::RefCell::new(port) })
+    }
+}
+
+impl Driver for SerialDriver {
+    fn name(&self) -> String { self.name.clone() }
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        use std::io::Write;
+        self.port.borrow_mut().write_all(data).map_err(|e| escpos::errors::PrinterError::Io(e.to_string()))
+    }
+    fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        use std::io::Read;
+        self.port.borrow_mut().read(buf).map_err(|e| escpos::errors::PrinterError::Io(e.to_string()))
+    }
+    fn flush(&self) -> escpos::errors::Result<()> {
+        use std::io::Write;
+        self.port.borrow_mut().flush().map_err(|e| escpos::errors::PrinterError::Io(e.to_string()))
+    }
+}
+
+/// Opens `target`; `timeout` bounds reads/writes.
+/// Returns the driver plus the target's label.
+fn open_driver(target: &Target, timeout: Option<Duration>) -> Result<(BoxedDriver, String), String> {
+    match target {
+        Target::Serial { port, baud, settings } => {
+            let port = normalize_com_port(port);
+            let driver = SerialDriver::open(&port, *baud, settings, timeout.unwrap_or(SERIAL_TIMEOUT))
+                .map_err(|e| format!("open {} @{}: {}", port, baud, e))?;
+            Ok((BoxedDriver(Box::new(driver)), port))
+        }
+        Target::Network { host, port } => {
+            let driver = NetworkDriver::open(host, *port, Some(timeout.unwrap_or(NET_CONNECT_TIMEOUT)))
+                .map_err(|e| format!("connect {}:{} failed: {}", host, port, e))?;
+            Ok((BoxedDriver(Box::new(driver)), target.label()))
+        }
+        &Target::Usb { vid, pid } => {
+            let driver = UsbDriver::open(vid, pid, timeout, None).map_err(|e| {
+                // 0 matches means "not plugged in"; 1+ means it is there but could not be claimed.
+                let found = count_usb_devices(vid, pid);
+                let hint = if found == 0 { "device not found" } else { "device found but could not be opened (permissions?)" };
+                format!("open USB {:04x}:{:04x} failed, {} matching device(s) found, {}: {}", vid, pid, found, hint, e)
+            })?;
+            Ok((BoxedDriver(Box::new(driver)), target.label()))
+        }
+    }
+}
+
+/// `open_driver`, retried for adapters that need a moment after the previous job.
+fn open_driver_with_retry(target: &Target, timeout: Option<Duration>) -> Result<(BoxedDriver, String), String> {
+    let attempts = get_open_retries() + 1;
+    let delay = get_open_retry_delay();
+    let mut last_err = String::new();
+    for attempt in 1..=attempts {
+        match open_driver(target, timeout) {
+            Ok(opened) => return Ok(opened),
+            Err(e) => {
+                last_err = e;
+                if attempt < attempts { std::thread::sleep(delay); }
+            }
+        }
+    }
+    Err(format!("{} (gave up after {} attempt{})", last_err, attempts, if attempts == 1 { "" } else { "s" }))
+}
+
+/// Tees everything written into `file`; with no printer attached it only writes the file.
+struct DumpDriver {
+    inner: Option<BoxedDriver>,
+    file: std::cell::RefCell<std::fs::File>,
+}
+
+impl Driver for DumpDriver {
+    fn name(&self) -> String { "dump".into() }
+    fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+        use std::io::Write;
+        self.file.borrow_mut().write_all(data).map_err(|e| escpos::errors::PrinterError::Io(e.to_string()))?;
+        match &self.inner { Some(d) => d.write(data), None => Ok(()) }
+    }
+    fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+        match &self.inner { Some(d) => d.read(buf), None => Ok(0) }
+    }
+    fn flush(&self) -> escpos::errors::Result<()> {
+        use std::io::Write;
+        self.file.borrow_mut().flush().map_err(|e| escpos::errors::PrinterError::Io(e.to_string()))?;
+        match &self.inner { Some(d) => d.flush(), None => Ok(()) }
+    }
+}
+
+/// The printer, wrapped to also dump the job to `PRINTER_DUMP_PATH` when set.
+/// With a dump path an unreachable printer is not an error: the file alone is the output.
+/// Writes are bounded by `PRINTER_WRITE_TIMEOUT_MS`.
+fn open_output(target: &Target) -> Result<(BoxedDriver, String), String> {
+    let timeout = Some(get_write_timeout());
+    let Some(path) = get_dump_path() else { return open_driver_with_retry(target, timeout) };
+    let file = std::fs::File::create(&path).map_err(|e| format!("dump file '{}': {}", path, e))?;
+    let (inner, target) = match open_driver_with_retry(target, timeout) {
+        Ok((d, t)) => (Some(d), format!("{} (dumped to {})", t, path)),
+        Err(_) => (None, format!("dump file {} (no printer reachable)", path)),
+    };
+    Ok((BoxedDriver(Box::new(DumpDriver { inner, file: std::cell::RefCell::new(file) })), target))
+}
+
+/// Closes the port right away instead of whenever the handle happens to drop, then
+/// waits `PRINTER_CLOSE_DELAY_MS`: on Windows the next open can fail while the OS is
+/// still releasing the handle. Callers hold the device's turn, so the next job waits too.
+/// Status queries close the same way: they tend to come right before a print.
+/// (`write_job` has already flushed the bytes to the OS.)
+fn close_output(driver: BoxedDriver) {
+    drop(driver);
+    std::thread::sleep(get_close_delay());
+}
+
+/// A bitmap as ESC * 24-dot double-density bands, one chunk (header, data, feed) per band.
+#[allow(non_snake_case)]
+fn band_chunks(gray: &GrayImage, threshold: u8, feed: &[u8]) -> Vec<Vec<u8>> {
+    let w = gray.width();
+    let n = w as u16;
+    let nL = (n & 0xFF) as u8;
+    let nH = ((n >> 8) & 0xFF) as u8;
+
+    let mut bands = Vec::new();
+    let mut y0 = 0u32;
+    while y0 < gray.height() {
+        let mut band = vec![0x1B, 0x2A, 33, nL, nH];
+        band.extend(pack_esc_star_24(gray, y0, threshold));
+        band.extend_from_slice(feed);
+        bands.push(band);
+        y0 += 24;
+    }
+    bands
+}
+
+/// Paper advance after each `ESC *` band: LF by default, `ESC J n` for an exact dot count
+/// (24 closes the seams LF leaves on some printers), nothing when `band_feed` is 0.
+fn band_feed(layout: &Layout) -> Vec<u8> {
+    match layout.band_feed {
+        None => vec![0x0A],
+        Some(0) => Vec::new(),
+        Some(n) => vec![0x1B, 0x4A, n],
+    }
+}
+
+/// `ESC 3 n` for `line_spacing_dots`, and `ESC 2` to go back to the printer's default
+/// (so the feed before the cut keeps its usual length); both empty when unset.
+fn line_spacing_bytes(layout: &Layout) -> (Vec<u8>, Vec<u8>) {
+    match layout.line_spacing_dots {
+        Some(n) => (vec![0x1B, 0x33, n], vec![0x1B, 0x32]),
+        None => (Vec::new(), Vec::new()),
+    }
+}
+
+/// Paper advance before the cut, per `feed_before_cut` / `feed_unit`.
+fn feed_before_cut(layout: &Layout) -> Vec<u8> {
+    match layout.feed_unit {
+        FeedUnit::Lines => vec![0x0A; layout.feed_before_cut as usize],
+        FeedUnit::Dots => vec![0x1B, 0x4A, layout.feed_before_cut],
+    }
+}
+/// Between the receipt and its stub: the usual feed, then a partial cut, or only
+/// the feed (plus any tear-off feed) on printers without a cutter.
+fn stub_cut(layout: &Layout) -> Vec<u8> {
+    let mut out = feed_before_cut(layout);
+    match layout.cut_mode {
+        CutMode::Full | CutMode::Partial => out.extend_from_slice(&[0x1D, 0x56, 0x01]),
+        CutMode::None => {}
+        CutMode::TearOff { feed_dots } => out.extend(feed_dots_bytes(feed_dots)),
+    }
+    out
+}
+/// `ESC p m t1 t2`: kick the cash drawer (times are in 2 ms units).
+fn drawer_pulse(layout: &Layout) -> [u8; 5] {
+    let t = |ms: u16| (ms / 2).clamp(1, 255) as u8;
+    [0x1B, 0x70, layout.drawer_pin.min(1), t(layout.drawer_on_ms), t(layout.drawer_off_ms)]
+}
+
+/// Print density for `layout.density` percent in the layout's vendor command; empty when unset.
+fn density_bytes(layout: &Layout) -> Vec<u8> {
+    let Some(pct) = layout.density else { return Vec::new() };
+    let pct = pct.min(100) as i32;
+    match layout.density_command {
+        DensityCommand::Epson => {
+            let step = ((pct - 50) as f32 * 6.0 / 50.0).round() as i32; // -6..=6, 0 = standard
+            vec![0x1D, 0x28, 0x4B, 0x02, 0x00, 0x31, step.rem_euclid(256) as u8] // negative as 250..=255
+        }
+        DensityCommand::Dc2 => vec![0x12, 0x23, (pct * 31 / 100) as u8],
+    }
+}
+
+/// `ESC B n t`: sound the buzzer `times` times for `duration_ms` each (50 ms units, 1-9).
+fn buzzer(times: u8, duration_ms: u16) -> [u8; 4] {
+    [0x1B, 0x42, times.clamp(1, 9), (duration_ms / 50).clamp(1, 9) as u8]
+}
+
+/// A bitmap in the layout's raster mode, as separately written chunks.
+/// With `rotate_180` the bitmap is turned first, so bands go out bottom band first
+/// and each band's columns right to left.
+fn image_chunks(gray: &GrayImage, layout: &Layout) -> Vec<Vec<u8>> {
+    let rotated;
+    let gray = if layout.rotate_180 {
+        rotated = image::imageops::rotate180(gray);
+        &rotated
+    } else {
+        gray
+    };
+    match layout.raster_mode {
+        RasterMode::EscStar24 => band_chunks(gray, layout.threshold, &band_feed(layout)),
+        RasterMode::GsV0 => vec![pack_gs_v0(gray, layout.threshold)],
+        RasterMode::Graphics => graphics_chunks(gray, layout.threshold),
+    }
+}
+fn image_bytes(gray: &GrayImage, layout: &Layout) -> Vec<u8> {
+    image_chunks(gray, layout).concat()
+}
+
+/// `ESC r n`: print color for the following lines (0 = black, 1 = red on two-color paper).
+const COLOR_BLACK: [u8; 3] = [0x1B, 0x72, 0x00];
+const COLOR_RED: [u8; 3] = [0x1B, 0x72, 0x01];
+
+/// The bitmap, cut into strips at the splices and red rows: native commands go
+/// between strips and each red strip is wrapped in `ESC r 1` / `ESC r 0`. Rotated,
+/// the pieces go out last first (each strip turned by `image_chunks`), so a splice
+/// still sits between the same two strips.
+fn rendered_chunks(rendered: &Rendered, layout: &Layout) -> Vec<Vec<u8>> {
+    let gray = &rendered.image;
+    let h = gray.height();
+    let mut cuts: Vec<u32> = rendered.splices.iter().map(|sp| sp.row.min(h))
+        .chain(rendered.red.iter().flat_map(|r| [r.start.min(h), r.end.min(h)]))
+        .chain([h])
+        .collect();
+    cuts.sort_unstable();
+    cuts.dedup();
+    let mut splices = rendered.splices.iter().peekable();
+    let mut pieces = Vec::new();
+    let mut start = 0u32;
+    for cut in cuts {
+        if cut > start {
+            let part = image::imageops::crop_imm(gray, 0, start, gray.width(), cut - start).to_image();
+            let mut chunks = image_chunks(&part, layout);
+            if rendered.red.iter().any(|r| r.contains(&start)) {
+                chunks.insert(0, COLOR_RED.to_vec());
+                chunks.push(COLOR_BLACK.to_vec());
+            }
+            pieces.push(chunks);
+        }
+        while let Some(sp) = splices.next_if(|sp| sp.row.min(h) <= cut) {
+            pieces.push(vec![sp.bytes.clone()]);
+        }
+        start = cut;
+    }
+    if layout.rotate_180 {
+        pieces.reverse();
+    }
+    pieces.concat()
+}
+
+/// A centered caption line (e.g. "نسخة العميل") as a small raster strip.
+fn caption_bytes(caption: &str, layout: &Layout) -> Result<Vec<u8>, String> {
+    let assets = Assets::load(None, layout)?;
+    let ts = assets.typesetter(layout)?;
+    let scale = PxScale::from(layout.fonts.footer);
+    let h = (layout.fonts.footer * 1.5).ceil() as u32;
+    let mut img: RgbImage = ImageBuffer::from_pixel(layout.paper_width_px, h, Rgb([255, 255, 255]));
+    draw_mixed_rtl_center(&mut img, &ts, scale, caption, 0, layout.paper_width_px as i32, 0);
+    Ok(image_bytes(&image::DynamicImage::ImageRgb8(img).to_luma8(), layout))
+}
+
+/// Everything between init and the feed/cut of one copy.
+struct JobBody {
+    chunks: Vec<Vec<u8>>, // written one by one: raster bands (and splices), or one block of text
+    height: Option<u32>,  // bitmap height in px; None for the native text modes
+    warnings: Vec<String>, // printed, but not quite as given (missing glyphs)
+}
+
+/// The body as native text or the rendered raster bands.
+fn job_body(data: &ReceiptData, layout: &Layout) -> Result<JobBody, String> {
+    if matches!(layout.render_mode, RenderMode::Text) && text_mode_supported(data, layout) {
+        Ok(JobBody { chunks: vec![render_text_mode(data, layout)?], height: None, warnings: Vec::new() })
+    } else if matches!(layout.render_mode, RenderMode::CodePage864) {
+        Ok(JobBody { chunks: vec![render_codepage(data, layout)?], height: None, warnings: Vec::new() })
+    } else {
+        let rendered = compose_receipt(data, layout)?;
+        let chunks = rendered_chunks(&rendered, layout);
+        Ok(JobBody {
+            chunks,
+            height: Some(rendered.image.height()),
+            warnings: missing_glyph_warning(&rendered.missing).into_iter().collect(),
+        })
+    }
+}
+
+/// `GS V m`: full or partial cut; for a tear bar, the feed past it.
+fn cut_bytes(layout: &Layout) -> Vec<u8> {
+    match layout.cut_mode {
+        CutMode::Full => vec![0x1D, 0x56, 0x00],
+        CutMode::Partial => vec![0x1D, 0x56, 0x01],
+        CutMode::None => Vec::new(),
+        CutMode::TearOff { feed_dots } => feed_dots_bytes(feed_dots),
+    }
+}
+/// `ESC J n` feeds of at most 255 dots each, adding up to `dots`.
+fn feed_dots_bytes(dots: u16) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut left = dots;
+    while left > 0 {
+        let n = left.min(255);
+        out.extend_from_slice(&[0x1B, 0x4A, n as u8]);
+        left -= n;
+    }
+    out
+}
+
+/// One labelled piece of a job; the label names it in write errors ("copy 1, band 12/40").
+type JobPart<'a> = (String, Cow<'a, [u8]>);
+
+/// Pieces written between paper checks, and how often a waiting job asks again.
+const PAPER_CHECK_EVERY: usize = 8;
+const PAPER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_PAPER_WAIT_MS: u32 = 120_000;
+
+/// Writes the job piece by piece straight to the driver, then flushes, so a stalled
+/// link fails on the piece it stalled on (each write is bounded by the driver's
+/// timeout) and the error tells a partial print from one that never started.
+/// Once `cancel` is set it sends `on_cancel` (feed and cut) instead of the remaining pieces.
+/// Every `PAPER_CHECK_EVERY` pieces it checks for paper per `paper`; a job stopped for
+/// lack of paper also gets `on_cancel`, so the printed part is cut once paper is back.
+fn write_job(driver: &BoxedDriver, parts: &[JobPart], on_cancel: &[u8], paper: PaperCheck, cancel: &AtomicBool) -> Result<usize, String> {
+    let total: usize = parts.iter().map(|(_, b)| b.len()).sum();
+    let mut sent = 0;
+    let mut bands = 0;
+    let mut polling = !matches!(paper, PaperCheck::Off);
+    let fail = |what: &str, sent: usize, e: escpos::errors::PrinterError| {
+        if sent == 0 {
+            format!("write failed at {}: {} (nothing was printed)", what, e)
+        } else {
+            format!("write failed at {} after {} of {} bytes: {} (partial print)", what, sent, total, e)
+        }
+    };
+    for (i, (label, bytes)) in parts.iter().enumerate() {
+        if polling && i % PAPER_CHECK_EVERY == 0 {
+            polling = match wait_for_paper(driver, paper, cancel, label, sent, total) {
+                Ok(polling) => polling,
+                Err(stopped) => {
+                    driver.write(on_cancel).and_then(|_| driver.flush()).map_err(|e| fail("cut after paper out", sent, e))?;
+                    return Err(stopped);
+                }
+            };
+        }
+        if cancel.swap(false, Ordering::SeqCst) {
+            driver.write(on_cancel).and_then(|_| driver.flush()).map_err(|e| fail("cancel", sent, e))?;
+            return Err(format!("cancelled at {} after {} band(s), {} of {} bytes", label, bands, sent, total));
+        }
+        driver.write(bytes).map_err(|e| fail(label.as_str(), sent, e))?;
+        sent += bytes.len();
+        bands += label.contains("band") as u32;
+    }
+    driver.flush().map_err(|e| fail("final flush", sent, e))?;
+    Ok(sent)
+}
+
+/// `Ok(true)` once the printer has paper (after waiting for a reload with
+/// `PaperCheck::Wait`); `Ok(false)` when it doesn't answer status requests.
+/// A cancel ends the wait; `write_job` then cancels as usual.
+fn wait_for_paper(driver: &BoxedDriver, check: PaperCheck, cancel: &AtomicBool, at: &str, sent: usize, total: usize) -> Result<bool, String> {
+    let paper_out = || read_status(driver).map(|s| s.paper_out);
+    match paper_out() {
+        Err(_) => return Ok(false),
+        Ok(false) => return Ok(true),
+        Ok(true) => {}
+    }
+    let stopped = if sent == 0 {
+        format!("out of paper at {} (nothing was printed)", at)
+    } else {
+        format!("out of paper at {} after {} of {} bytes (partial print)", at, sent, total)
+    };
+    let PaperCheck::Wait { timeout_ms } = check else { return Err(stopped) };
+    let deadline = std::time::Instant::now() + Duration::from_millis(timeout_ms as u64);
+    while std::time::Instant::now() < deadline {
+        std::thread::sleep(PAPER_POLL_INTERVAL);
+        if cancel.load(Ordering::SeqCst) || matches!(paper_out(), Ok(false)) {
+            return Ok(true);
+        }
+    }
+    Err(format!("{}; no paper loaded within {} s", stopped, timeout_ms / 1000))
+}
+
+/// What a print job did, for monitoring print times and paper use.
+#[derive(Serialize)]
+struct PrintReport {
+    message: String,        // "✅ Receipt printed on COM7", as the string-returning commands give it
+    height_px: Option<u32>, // bitmap height of one copy; None for the native text modes
+    bands: u32,             // 24-dot bands of receipt bitmap sent, all copies
+    bytes: usize,           // bytes written to the printer
+    elapsed_ms: u64,        // render (or cache lookup) plus transfer
+    warnings: Vec<String>,  // printed anyway, e.g. characters the font lacks
+}
+
+/// Prints the receipt and, with `PRINTER_LOG_PATH` set, logs the outcome.
+fn do_print(data: &ReceiptData, layout: &Layout, target: &Target, cancel: &AtomicBool) -> Result<PrintReport, String> {
+    let result = send_receipt(data, layout, target, cancel);
+    log_print(data, target, &result);
+    result
+}
+
+/// Appends `{"time", "port", "invoice_no", "items", "total", "bytes", "ok", "error"}`
+/// to the log. Best effort: a log that can't be written never fails the print.
+fn log_print(data: &ReceiptData, target: &Target, result: &Result<PrintReport, String>) {
+    use std::io::Write;
+    let Some(path) = get_log_path() else { return };
+    let record = serde_json::json!({
+        "time": chrono::Local::now().to_rfc3339(),
+        "port": target.label(),
+        "invoice_no": data.invoice_no,
+        "items": data.items.len(),
+        "total": data.grand_total(),
+        "bytes": result.as_ref().ok().map(|r| r.bytes),
+        "ok": result.is_ok(),
+        "error": result.as_ref().err(),
+    });
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(&path);
+    if let Ok(mut file) = file {
+        let _ = writeln!(file, "{}", record);
+    }
+}
+
+fn send_receipt(data: &ReceiptData, layout: &Layout, target: &Target, cancel: &AtomicBool) -> Result<PrintReport, String> {
+    let started = std::time::Instant::now();
+    // Rendered once (before touching the port), sent per copy
+    let body = cached_job_body(data, layout)?;
+    let copies = data.copies.max(1) as usize;
+    let (spacing, default_spacing) = line_spacing_bytes(layout);
+
+    let mut job: Vec<JobPart> = vec![("init".into(), Cow::Borrowed(&[0x1B, 0x40][..]))];
+    job.push(("density".into(), Cow::Owned(density_bytes(layout))));
+    for copy in 0..copies {
+        let n = copy + 1;
+        // The caption heads the copy, which upside down means it goes out after the body;
+        // either way the feed and cut follow, so the cut still lands above the store name
+        let caption = match layout.copy_captions.get(copy).filter(|c| !c.is_empty()) {
+            Some(c) => Some((format!("copy {}, caption", n), Cow::Owned(caption_bytes(c, layout)?))),
+            None => None,
+        };
+        let (before, after) = if layout.rotate_180 { (None, caption) } else { (caption, None) };
+        job.push((format!("copy {}, line spacing", n), Cow::Borrowed(spacing.as_slice())));
+        job.extend(before);
+        for (i, band) in body.chunks.iter().enumerate() {
+            job.push((format!("copy {}, band {}/{}", n, i + 1, body.chunks.len()), Cow::Borrowed(band.as_slice())));
+        }
+        job.extend(after);
+        job.push((format!("copy {}, default spacing", n), Cow::Borrowed(default_spacing.as_slice())));
+        // feed, drawer (after the whole raster so it can't split a band, first copy only) & cut
+        job.push((format!("copy {}, feed", n), Cow::Owned(feed_before_cut(layout))));
+        if data.open_drawer && copy == 0 {
+            job.push(("drawer".into(), Cow::Owned(drawer_pulse(layout).to_vec())));
+        }
+        job.push((format!("copy {}, cut", n), Cow::Owned(cut_bytes(layout))));
+    }
+    if layout.beep_on_print {
+        job.push(("beep".into(), Cow::Owned(buzzer(BEEP_ON_PRINT_TIMES, BEEP_ON_PRINT_MS).to_vec())));
+    }
+
+    let (driver, target) = open_output(target)?;
+    let sent = write_job(&driver, &job, &[default_spacing.clone(), feed_before_cut(layout), cut_bytes(layout)].concat(), layout.paper_check, cancel);
+    close_output(driver);
+    let bytes = sent?;
+    let mut message = if copies > 1 {
+        format!("✅ {} copies printed on {}", copies, target)
+    } else {
+        format!("✅ Receipt printed on {}", target)
+    };
+    if !body.warnings.is_empty() {
+        message = format!("{} (⚠️ {})", message, body.warnings.join("; "));
+    }
+    Ok(PrintReport {
+        message,
+        height_px: body.height,
+        bands: body.height.map_or(0, |h| h.div_ceil(24)) * copies as u32,
+        bytes,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        warnings: body.warnings.clone(),
+    })
+}
+
+// ---------------- Print queue ----------------
+
+/// One lock per device (by `Target::label`): jobs for the same printer wait their
+/// turn instead of failing on a busy port or interleaving bytes; other printers run in parallel.
+static DEVICE_LOCKS: Mutex<Vec<(String, Arc<tokio::sync::Mutex<()>>)>> = Mutex::new(Vec::new());
+
+/// One cancel flag per device, set by `cancel_print` and seen by the job holding its turn.
+static CANCEL_FLAGS: Mutex<Vec<(String, Arc<AtomicBool>)>> = Mutex::new(Vec::new());
+
+fn cancel_flag(key: &str) -> Arc<AtomicBool> {
+    let mut flags = CANCEL_FLAGS.lock().unwrap_or_else(|e| e.into_inner());
+    match flags.iter().find(|(k, _)| k == key) {
+        Some((_, f)) => f.clone(),
+        None => {
+            let f = Arc::new(AtomicBool::new(false));
+            flags.push((key.to_string(), f.clone()));
+            f
+        }
+    }
+}
+
+/// Exclusive use of a device; dropping it hands the device to the next job.
+struct DeviceTurn {
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+    cancel: Arc<AtomicBool>, // this device's flag, cleared when the turn starts
+}
+
+/// Waits (up to `PRINTER_QUEUE_TIMEOUT_MS`) for exclusive use of the target.
+/// A cancel sent before the turn starts is dropped: it only stops a job already sending.
+async fn device_turn(target: &Target) -> Result<DeviceTurn, String> {
+    let key = target.label();
+    let lock = {
+        let mut locks = DEVICE_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        match locks.iter().find(|(k, _)| *k == key) {
+            Some((_, l)) => l.clone(),
+            None => {
+                let l = Arc::new(tokio::sync::Mutex::new(()));
+                locks.push((key.clone(), l.clone()));
+                l
+            }
+        }
+    };
+    let wait = get_queue_timeout();
+    let guard = tokio::time::timeout(wait, lock.lock_owned())
+        .await
+        .map_err(|_| format!("{} still busy with another job after {} ms; giving up", key, wait.as_millis()))?;
+    let cancel = cancel_flag(&key);
+    cancel.store(false, Ordering::SeqCst);
+    Ok(DeviceTurn { _guard: guard, cancel })
+}
+
+/// Runs blocking device I/O (open, write, close delay) on tokio's blocking pool,
+/// so a slow printer doesn't stall the runtime's workers.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, String> + Send + 'static) -> Result<T, String> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| format!("print job panicked: {}", e))?
+}
+
+/// `do_print` once the target is free; the turn is held until the job is done.
+async fn print_queued(data: &ReceiptData, layout: &Layout, target: &Target) -> Result<PrintReport, String> {
+    let turn = device_turn(target).await?;
+    let (data, layout, target, cancel) = (data.clone(), layout.clone(), target.clone(), turn.cancel.clone());
+    blocking(move || do_print(&data, &layout, &target, &cancel)).await
+}
+
+// ---------------- Render cache ----------------
+
+const RENDER_CACHE_SIZE: usize = 8;
+
+/// Job bodies of recent receipts, most recently used first. Keyed by the whole
+/// (data, layout) pair, so any layout or font path change is a different entry.
+static RENDER_CACHE: Mutex<VecDeque<(u64, Arc<JobBody>)>> = Mutex::new(VecDeque::new());
+
+fn cache_key(data: &ReceiptData, layout: &Layout) -> Result<u64, String> {
+    let json = serde_json::to_string(&(data, layout)).map_err(|e| format!("cache key: {}", e))?;
+    let mut h = std::collections::hash_map::DefaultHasher::new();
+    json.hash(&mut h);
+    Ok(h.finish())
+}
+
+/// `job_body`, rendered only on a cache miss. The lock is not held while rendering.
+fn cached_job_body(data: &ReceiptData, layout: &Layout) -> Result<Arc<JobBody>, String> {
+    let key = cache_key(data, layout)?;
+    let cache = || RENDER_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    {
+        let mut c = cache();
+        if let Some(i) = c.iter().position(|(k, _)| *k == key) {
+            let hit = c.remove(i).unwrap();
+            let body = hit.1.clone();
+            c.push_front(hit);
+            return Ok(body);
+        }
+    }
+    let body = Arc::new(job_body(data, layout)?);
+    let mut c = cache();
+    c.retain(|(k, _)| *k != key);
+    c.push_front((key, body.clone()));
+    c.truncate(RENDER_CACHE_SIZE);
+    Ok(body)
+}
+
+// ---------------- Tauri Command ----------------
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn print_receipt(
+    title: String,
+    time: String,
+    number: String,
+    items: Vec<FrontendItem>,
+    total: f32,           // overall total (printed as-is)
+    discount: Option<f32>,
+    footer: FrontendFooter,
+    qr: Option<String>,
+    logo_path: Option<String>,
+    barcode: Option<BarcodeSpec>,
+    open_drawer: Option<bool>,
+    tax_rate: Option<f32>,
+    amount_paid: Option<f32>,
+    payment_method: Option<String>,
+    currency: Option<String>,
+    paper_width_px: Option<u32>, // e.g. 384 for 58 mm paper
+    kitchen: Option<bool>,       // kitchen ticket: names and quantities only
+    copies: Option<u8>,
+) -> Result<String, String> {
+    let mapped_items: Vec<Item> = items.into_iter()
+        .map(|i| Item {
+            name: i.name,
+            qty_str: i.qty.into_display(), // preserve input representation
+            price: i.price,
+            total: i.total,
+            discount: i.discount,
+            tax_rate: i.tax_rate,
+            notes: i.notes,
+            unit: i.unit,
+            sku: i.sku,
+            force_ltr: i.force_ltr,
+        })
+        .collect();
+
+    let data = ReceiptData {
+        store_name: title,
+        store: None,
+        date_time_line: time,
+        timestamp: None,
+        date_time_format: DateTimeFormat::default(),
+        invoice_no: number,
+        items: mapped_items,
+        discount: discount.unwrap_or(0.0),
+        total,
+        footer_lines: footer.into(),
+        qr,
+        logo_path,
+        barcode,
+        open_drawer: open_drawer.unwrap_or(false),
+        tax_rate: tax_rate.unwrap_or(0.0),
+        amount_paid,
+        payment_method,
+        currency,
+        copies: copies.unwrap_or(1),
+        qr_spec: QrSpec::default(),
+        stub: None,
+        watermark: None,
+    };
+
+    let layout = paper_width_px.map(Layout::for_width).unwrap_or_default();
+    let layout = if kitchen.unwrap_or(false) { layout.kitchen() } else { layout };
+    print_queued(&data, &layout, &get_target()?).await.map(|r| r.message)
+}
+
+/// Parses the JSON payloads shared by the JSON-driven commands.
+/// A missing or blank layout means `Layout::default()`; a given one is validated.
+fn parse_receipt_json(data_json: &str, layout_json: Option<&str>) -> Result<(ReceiptData, Layout), String> {
+    let data: ReceiptData = serde_json::from_str(data_json).map_err(|e| format!("data JSON: {}", e))?;
+    Ok((data, parse_layout_json(layout_json)?))
+}
+fn parse_layout_json(layout_json: Option<&str>) -> Result<Layout, String> {
+    match layout_json.filter(|s| !s.trim().is_empty()) {
+        Some(j) => Layout::from_json_detailed(j).map_err(|e| format!("layout JSON: {}", e)),
+        None => Ok(Layout::default()),
+    }
+}
+
+// ---------------- Layout presets ----------------
+
+/// Matches `identifier` in tauri.conf.json.
+const APP_IDENTIFIER: &str = "com.ahmedelshentenawy.tauri-pos-printer";
+
+/// `<dir>/<name>.json`, for names of letters (any script), digits, spaces, '-' and '_'.
+fn preset_path(dir: &std::path::Path, name: &str) -> Result<std::path::PathBuf, String> {
+    let name = name.trim();
+    let ok = !name.is_empty() && name.chars().count() <= 64
+        && name.chars().all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'));
+    if !ok {
+        return Err(format!("preset name '{}' must be 1-64 letters, digits, spaces, '-' or '_'", name));
+    }
+    Ok(dir.join(format!("{}.json", name)))
+}
+
+/// Validates the layout and stores it (every field, defaults filled in) under `name`.
+/// An existing preset is only replaced with `overwrite`. Returns the stored JSON.
+fn save_preset(dir: &std::path::Path, name: &str, layout_json: &str, overwrite: bool) -> Result<String, String> {
+    let path = preset_path(dir, name)?;
+    let layout = Layout::from_json_detailed(layout_json).map_err(|e| format!("layout JSON: {}", e))?;
+    if path.exists() && !overwrite {
+        return Err(format!("preset '{}' already exists", name.trim()));
+    }
+    let json = serde_json::to_string_pretty(&layout).map_err(|e| format!("layout JSON: {}", e))?;
+    std::fs::create_dir_all(dir).map_err(|e| format!("presets directory '{}': {}", dir.display(), e))?;
+    // written aside and renamed, so a crash never leaves half a preset
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, &json)
+        .and_then(|_| std::fs::rename(&tmp, &path))
+        .map_err(|e| format!("preset '{}': {}", path.display(), e))?;
+    Ok(json)
+}
+fn load_preset(dir: &std::path::Path, name: &str) -> Result<String, String> {
+    let path = preset_path(dir, name)?;
+    std::fs::read_to_string(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => format!("no preset named '{}'", name.trim()),
+        _ => format!("preset '{}': {}", path.display(), e),
+    })
+}
+/// Preset names, sorted; an absent directory just means none saved yet.
+fn list_presets(dir: &std::path::Path) -> Result<Vec<String>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("presets directory '{}': {}", dir.display(), e)),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".json").map(str::to_string))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Saves a layout JSON as a named preset; returns the stored layout JSON.
+#[tauri::command]
+async fn save_layout_preset(name: String, layout_json: String, overwrite: Option<bool>) -> Result<String, String> {
+    save_preset(&get_presets_dir()?, &name, &layout_json, overwrite.unwrap_or(false))
+}
+
+/// The layout JSON saved under `name`.
+#[tauri::command]
+async fn load_layout_preset(name: String) -> Result<String, String> {
+    load_preset(&get_presets_dir()?, &name)
+}
+
+#[tauri::command]
+async fn list_layout_presets() -> Result<Vec<String>, String> {
+    list_presets(&get_presets_dir()?)
+}
+
+/// Checks a layout JSON without printing; errors are structured (`LayoutError`).
+#[tauri::command]
+async fn check_layout_json(layout_json: String) -> Result<(), LayoutError> {
+    Layout::from_json_detailed(&layout_json).map(|_| ())
+}
+
+/// Prints a `ReceiptData` JSON payload with an optional `Layout` JSON, or the layout
+/// saved as `preset`. `baud` and `serial` override the environment's serial settings
+/// for this job (ignored for network and USB printers). Returns the job's `PrintReport`.
+#[tauri::command]
+async fn print_receipt_json(
+    data_json: String,
+    layout_json: Option<String>,
+    baud: Option<u32>,
+    serial: Option<SerialSettings>,
+    preset: Option<String>,
+) -> Result<PrintReport, String> {
+    let layout_json = match (layout_json.filter(|j| !j.trim().is_empty()), preset) {
+        (Some(_), Some(_)) => return Err("give either layout_json or preset, not both".into()),
+        (None, Some(name)) => Some(load_preset(&get_presets_dir()?, &name)?),
+        (json, None) => json,
+    };
+    let (data, layout) = parse_receipt_json(&data_json, layout_json.as_deref())?;
+    let mut target = get_target()?;
+    if let Target::Serial { baud: b, settings, .. } = &mut target {
+        if let Some(v) = baud { *b = v; }
+        if let Some(v) = serial { *settings = v; }
+    }
+    print_queued(&data, &layout, &target).await
+}
+
+/// Receipt data and its layout in one JSON object; `layout` may be left out.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReceiptDoc {
+    data: ReceiptData,
+    #[serde(default)]
+    layout: Option<Layout>,
+}
+
+/// `print_receipt_json` taking `{ "data": {...}, "layout": {...} }` as a single payload.
+#[tauri::command]
+async fn print_receipt_doc(doc_json: String) -> Result<PrintReport, String> {
+    let doc: ReceiptDoc = serde_json::from_str(&doc_json).map_err(|e| format!("receipt doc JSON: {}", e))?;
+    let layout = match doc.layout {
+        Some(layout) => layout.checked().map_err(|e| format!("layout JSON: {}", e))?,
+        None => Layout::default(),
+    };
+    print_queued(&doc.data, &layout, &get_target()?).await
+}
+
+/// Prints a `ReceiptData` JSON file written by another program (e.g. a legacy POS
+/// dropping invoices into a folder). A UTF-8 BOM, as Windows tools often write, is skipped.
+#[tauri::command]
+async fn print_receipt_file(path: String, layout_json: Option<String>) -> Result<String, String> {
+    let bytes = std::fs::read(&path).map_err(|e| format!("invoice file '{}': {}", path, e))?;
+    let text = std::str::from_utf8(&bytes).map_err(|e| format!("invoice file '{}': not UTF-8: {}", path, e))?;
+    let text = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+    let data: ReceiptData = serde_json::from_str(text).map_err(|e| format!("invoice file '{}': {}", path, e))?;
+    let layout = parse_layout_json(layout_json.as_deref())?;
+    print_queued(&data, &layout, &get_target()?).await.map(|r| r.message)
+}
+
+/// Stops the job being sent to `device` (a label like "COM7" or "192.168.1.50:9100";
+/// the configured printer when omitted) at its next band: the rest is dropped and the
+/// paper fed and cut; that job's command fails with "cancelled at ... after N band(s)".
+/// Jobs on other printers are unaffected. Deliberately outside the device queue,
+/// which the running job holds.
+#[tauri::command]
+async fn cancel_print(device: Option<String>) -> Result<String, String> {
+    let key = match device {
+        Some(d) => normalize_com_port(d.trim()), // as `Target::label` spells serial ports
+        None => get_target()?.label(),
+    };
+    cancel_flag(&key).store(true, Ordering::SeqCst);
+    Ok(format!("Cancel requested on {}", key))
+}
+
+/// Sounds the printer's buzzer (order-ready alert) without printing anything.
+#[tauri::command]
+async fn beep(times: u8, duration_ms: u16) -> Result<String, String> {
+    let target = get_target()?;
+    let turn = device_turn(&target).await?;
+    let cancel = turn.cancel.clone();
+    blocking(move || {
+        let (driver, target) = open_driver_with_retry(&target, Some(get_write_timeout()))?;
+        let sent = write_job(&driver, &[("beep".into(), Cow::Owned(buzzer(times, duration_ms).to_vec()))], &[], PaperCheck::Off, &cancel);
+        close_output(driver);
+        sent.map(|_| format!("✅ Beeped on {}", target))
+    })
+    .await
+}
+
+/// Drops every cached render (e.g. after replacing a font or logo file in place).
+#[tauri::command]
+async fn clear_receipt_cache() -> Result<(), String> {
+    RENDER_CACHE.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    Ok(())
+}
+
+/// Prints the calibration page with the given (or default) layout.
+#[tauri::command]
+async fn print_calibration(layout_json: Option<String>) -> Result<String, String> {
+    let layout = parse_layout_json(layout_json.as_deref())?;
+    let gray = render_calibration(&layout)?;
+    let target = print_gray_image(&gray, &layout).await?;
+    Ok(format!("✅ Calibration page printed on {}", target))
+}
+
+/// Prints a finished bitmap (init, density, bands, feed, cut) once the printer is
+/// free; returns the printer's label.
+async fn print_gray_image(gray: &GrayImage, layout: &Layout) -> Result<String, String> {
+    let target = get_target()?;
+    let turn = device_turn(&target).await?;
+    let (gray, layout, cancel) = (gray.clone(), layout.clone(), turn.cancel.clone());
+    blocking(move || send_gray_image(&gray, &layout, &target, &cancel)).await
+}
+
+/// The job behind `print_gray_image`, on the calling thread.
+fn send_gray_image(gray: &GrayImage, layout: &Layout, target: &Target, cancel: &AtomicBool) -> Result<String, String> {
+    let mut job: Vec<JobPart> = vec![("init".into(), Cow::Borrowed(&[0x1B, 0x40][..]))];
+    job.push(("density".into(), Cow::Owned(density_bytes(layout))));
+    let (spacing, default_spacing) = line_spacing_bytes(layout);
+    job.push(("line spacing".into(), Cow::Owned(spacing)));
+    let bands = image_chunks(gray, layout);
+    let n = bands.len();
+    job.extend(bands.into_iter().enumerate().map(|(i, b)| (format!("band {}/{}", i + 1, n), Cow::Owned(b))));
+    job.push(("default spacing".into(), Cow::Owned(default_spacing.clone())));
+    job.push(("feed".into(), Cow::Owned(feed_before_cut(layout))));
+    job.push(("cut".into(), Cow::Owned(cut_bytes(layout))));
+
+    let (driver, target) = open_output(target)?;
+    let sent = write_job(&driver, &job, &[default_spacing, feed_before_cut(layout), cut_bytes(layout)].concat(), layout.paper_check, cancel);
+    close_output(driver);
+    sent.map(|_| target)
+}
+
+/// An image (coupon, flyer) fitted to the paper width and centered, logo-style
+/// binarization (`dither` / `logo_threshold`) applied.
+fn paper_image(img: image::DynamicImage, layout: &Layout) -> GrayImage {
+    let fitted = binarize_logo(to_gray_fit(img, layout.paper_width_px), layout);
+    let mut page = GrayImage::from_pixel(layout.paper_width_px, fitted.height(), Luma([255]));
+    image::imageops::overlay(&mut page, &fitted, ((layout.paper_width_px - fitted.width()) / 2) as i64, 0);
+    page
+}
+
+/// Prints an image file (PNG, JPEG, ...) on its own, scaled down to the paper width.
+#[tauri::command]
+async fn print_image(path: String, layout_json: Option<String>) -> Result<String, String> {
+    let layout = parse_layout_json(layout_json.as_deref())?;
+    let img = image::open(&path).map_err(|e| format!("image '{}': {}", path, e))?;
+    let target = print_gray_image(&paper_image(img, &layout), &layout).await?;
+    Ok(format!("✅ Image printed on {}", target))
+}
+
+/// `print_image` for base64-encoded image bytes (a data URL prefix is accepted).
+#[tauri::command]
+async fn print_image_base64(image_base64: String, layout_json: Option<String>) -> Result<String, String> {
+    let layout = parse_layout_json(layout_json.as_deref())?;
+    let b64 = image_base64.trim();
+    let b64 = b64.split_once(";base64,").map_or(b64, |(_, data)| data);
+    let bytes = BASE64.decode(b64).map_err(|e| format!("base64: {}", e))?;
+    let img = image::load_from_memory(&bytes).map_err(|e| format!("image: {}", e))?;
+    let target = print_gray_image(&paper_image(img, &layout), &layout).await?;
+    Ok(format!("✅ Image printed on {}", target))
+}
+
+#[derive(Serialize)]
+struct TargetOutcome {
+    target: String,
+    ok: bool,
+    message: String, // success message or the error
+}
+
+/// Prints the same receipt on each printer in `targets_json` (a JSON array of
+/// `Target`s), in order. A failing printer is reported and the rest still print.
+#[tauri::command]
+async fn print_receipt_multi(data_json: String, targets_json: String, layout_json: Option<String>) -> Result<Vec<TargetOutcome>, String> {
+    let (data, layout) = parse_receipt_json(&data_json, layout_json.as_deref())?;
+    let targets: Vec<Target> = serde_json::from_str(&targets_json).map_err(|e| format!("targets JSON: {}", e))?;
+    if targets.is_empty() {
+        return Err("targets JSON: no printers given".into());
+    }
+    let mut outcomes = Vec::with_capacity(targets.len());
+    for t in &targets {
+        let result = print_queued(&data, &layout, t).await.map(|r| r.message);
+        outcomes.push(TargetOutcome { target: t.label(), ok: result.is_ok(), message: result.unwrap_or_else(|e| e) });
+    }
+    Ok(outcomes)
+}
+
+/// Binarizes like the printer does so previews show the real output.
+fn threshold_image(gray: &GrayImage, threshold: u8) -> GrayImage {
+    GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+        let Luma([v]) = *gray.get_pixel(x, y);
+        Luma([if v <= threshold { 0 } else { 255 }])
+    })
+}
+
+fn png_data_url(img: &GrayImage) -> Result<String, String> {
+    let mut buf = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)
+        .map_err(|e| format!("encode PNG: {}", e))?;
+    Ok(format!("data:image/png;base64,{}", BASE64.encode(&buf)))
+}
+
+// ---------------- PDF ----------------
+
+/// Single-page PDF holding the thresholded receipt as a 1-bit image,
+/// `layout.pdf_width_mm` wide and as tall as the aspect ratio makes it.
+fn receipt_pdf(gray: &GrayImage, layout: &Layout) -> Vec<u8> {
+    let (w, h) = gray.dimensions();
+    let row_bytes = w.div_ceil(8) as usize;
+    let mut bits = vec![0u8; row_bytes * h as usize]; // DeviceGray: 1 = white
+    for (x, y, p) in gray.enumerate_pixels() {
+        if p.0[0] > layout.threshold {
+            bits[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    let pixels = run_length(&bits);
+    let page_w = layout.pdf_width_mm * 72.0 / 25.4; // pt
+    let page_h = page_w * h as f32 / w as f32;
+    let (catalog, pages, page, image, contents) = (Ref::new(1), Ref::new(2), Ref::new(3), Ref::new(4), Ref::new(5));
+    let image_name = Name(b"Im0");
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog).pages(pages);
+    pdf.pages(pages).kids([page]).count(1);
+    {
+        let mut p = pdf.page(page); // each writer completes its object when dropped
+        p.parent(pages).media_box(PdfRect::new(0.0, 0.0, page_w, page_h)).contents(contents);
+        p.resources().x_objects().pair(image_name, image);
+    }
+    {
+        let mut img = pdf.image_xobject(image, &pixels);
+        img.width(w as i32).height(h as i32).bits_per_component(1);
+        img.color_space().device_gray();
+        img.filter(Filter::RunLengthDecode);
+    }
+    let mut content = Content::new();
+    content.save_state().transform([page_w, 0.0, 0.0, page_h, 0.0, 0.0]).x_object(image_name).restore_state();
+    pdf.stream(contents, &content.finish());
+    pdf.finish()
+}
+
+/// PDF `RunLengthDecode` encoding: runs of up to 128 equal bytes as (257 - n, byte),
+/// everything else as literal stretches of up to 128 bytes; 128 ends the data.
+fn run_length(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..].iter().take(128).take_while(|b| **b == data[i]).count();
+        if run > 1 {
+            out.extend_from_slice(&[(257 - run) as u8, data[i]]);
+            i += run;
+        } else {
+            let start = i;
+            while i < data.len() && i - start < 128 && data.get(i + 1) != Some(&data[i]) {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+    out.push(128);
+    out
+}
+
+/// Renders without printing; returns the receipt as a PNG data URL.
+#[tauri::command]
+async fn preview_receipt_png(data_json: String, layout_json: Option<String>) -> Result<String, String> {
+    let (data, layout) = parse_receipt_json(&data_json, layout_json.as_deref())?;
+    let gray = render_receipt(&data, &layout)?;
+    png_data_url(&threshold_image(&gray, layout.threshold))
+}
+
+/// The receipt as a one-page PDF (base64), e.g. to email; the page is
+/// `layout.pdf_width_mm` wide so it prints at paper scale on A4 too.
+#[tauri::command]
+async fn receipt_to_pdf(data_json: String, layout_json: Option<String>) -> Result<String, String> {
+    let (data, layout) = parse_receipt_json(&data_json, layout_json.as_deref())?;
+    let gray = render_receipt(&data, &layout)?;
+    Ok(BASE64.encode(receipt_pdf(&gray, &layout)))
+}
+
+/// Column edges, row positions and height of the bitmap receipt, without rendering it.
+#[tauri::command]
+async fn measure_receipt_json(data_json: String, layout_json: Option<String>) -> Result<ReceiptMetrics, String> {
+    let (data, layout) = parse_receipt_json(&data_json, layout_json.as_deref())?;
+    measure_receipt(&data, &layout)
+}
+
+/// Sends base64-decoded bytes to the printer verbatim (no ESC @ init, no cut).
+/// For debugging and for commands without a wrapper yet (buzzer, custom fonts).
+#[tauri::command]
+async fn print_raw(bytes_base64: String) -> Result<String, String> {
+    let bytes = BASE64.decode(bytes_base64.trim()).map_err(|e| format!("base64: {}", e))?;
+    if bytes.is_empty() {
+        return Err("empty payload: nothing to send".into());
+    }
+    let target = get_target()?;
+    let turn = device_turn(&target).await?;
+    let cancel = turn.cancel.clone();
+    blocking(move || {
+        let (driver, target) = open_driver_with_retry(&target, Some(get_write_timeout()))?;
+        let sent = write_job(&driver, &[("payload".into(), Cow::Borrowed(bytes.as_slice()))], &[], PaperCheck::Off, &cancel);
+        close_output(driver);
+        sent.map(|n| format!("✅ Sent {} bytes to {}", n, target))
+    })
+    .await
+}
+
+#[derive(Serialize)]
+struct PrinterStatus {
+    paper_out: bool,
+    cover_open: bool,
+    error: bool,
+}
+
+/// Sends real-time `DLE EOT n` and reads back its status byte.
+/// A missing reply is reported as a timeout rather than a generic I/O error.
+fn query_status(driver: &BoxedDriver, n: u8) -> Result<u8, String> {
+    driver.write(&[0x10, 0x04, n]).map_err(|e| format!("status request {}: {}", n, e))?;
+    driver.flush().map_err(|e| format!("status request {}: {}", n, e))?;
+    let timeout = || format!("timeout: no reply to status request {} within {} ms", n, STATUS_TIMEOUT.as_millis());
+    let mut buf = [0u8; 1];
+    match driver.read(&mut buf) {
+        Ok(1) => Ok(buf[0]),
+        Ok(_) => Err(timeout()),
+        Err(e) if e.to_string().to_lowercase().contains("timed out") => Err(timeout()),
+        Err(e) => Err(format!("status request {}: {}", n, e)),
+    }
+}
+
+/// Quick "is the printer there" check (e.g. at shift start): `DLE EOT 1` answered with
+/// a well-formed status byte (bits 1 and 4 set, 0 and 7 clear) within `STATUS_TIMEOUT`.
+/// An unreachable or silent printer gives `false`; only bad configuration is an error.
+#[tauri::command]
+async fn ping_printer() -> Result<bool, String> {
+    let target = get_target()?;
+    let _turn = device_turn(&target).await?;
+    blocking(move || {
+        let Ok((driver, _)) = open_driver(&target, Some(STATUS_TIMEOUT)) else { return Ok(false) };
+        let alive = query_status(&driver, 1).is_ok_and(|b| b & 0x93 == 0x12);
+        close_output(driver);
+        Ok(alive)
+    })
+    .await
+}
+
+#[tauri::command]
+async fn printer_status() -> Result<PrinterStatus, String> {
+    let target = get_target()?;
+    let _turn = device_turn(&target).await?;
+    blocking(move || {
+        let (driver, _) = open_driver(&target, Some(STATUS_TIMEOUT))?;
+        let status = read_status(&driver);
+        close_output(driver);
+        status
+    })
+    .await
+}
+
+fn read_status(driver: &BoxedDriver) -> Result<PrinterStatus, String> {
+    let offline = query_status(driver, 2)?; // bit2 cover open, bit5 stopped on paper end, bit6 error
+    let paper = query_status(driver, 4)?;    // bits5-6 roll paper end
+    Ok(PrinterStatus {
+        paper_out: offline & 0x20 != 0 || paper & 0x60 != 0,
+        cover_open: offline & 0x04 != 0,
+        error: offline & 0x40 != 0,
+    })
+}
+
+#[derive(Serialize)]
+struct PortInfo {
+    name: String,                  // ready to use as PRINTER_COM_PORT ("COM7", "/dev/ttyUSB0")
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial_number: Option<String>,
+}
+
+#[tauri::command]
+async fn list_serial_ports() -> Result<Vec<PortInfo>, String> {
+    let ports = serialport::available_ports().map_err(|e| format!("list serial ports: {}", e))?;
+    Ok(ports.into_iter()
+        .map(|p| {
+            // Undo the \\.\ prefix Windows uses for COM10+ so names round-trip through normalize_com_port.
+            let name = p.port_name.trim_start_matches("\\\\.\\").to_string();
+            match p.port_type {
+                serialport::SerialPortType::UsbPort(usb) => PortInfo {
+                    name,
+                    manufacturer: usb.manufacturer,
+                    product: usb.product,
+                    serial_number: usb.serial_number,
+                },
+                _ => PortInfo { name, manufacturer: None, product: None, serial_number: None },
+            }
+        })
+        .collect())
+}
+
+// ---------------- App entry ----------------
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    tauri::Builder::default()
+        .invoke_handler(tauri::generate_handler![ print_receipt, list_serial_ports, preview_receipt_png, receipt_to_pdf, measure_receipt_json, printer_status, ping_printer, print_raw, print_receipt_json, print_receipt_doc, print_receipt_file, print_receipt_multi, print_calibration, print_image, print_image_base64, clear_receipt_cache, cancel_print, beep, check_layout_json, save_layout_preset, load_layout_preset, list_layout_presets ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    // ---------------- Fixtures ----------------
+
+    /// The receipt from the demo page (src/App.tsx).
+    fn sample_receipt() -> ReceiptData {
+        serde_json::from_value(serde_json::json!({
+            "store_name": "اسواق ابو عمر",
+            "date_time_line": "٤ نوفمبر - ٤:٠٩ صباحا",
+            "invoice_no": "123456",
+            "items": [
+                { "name": "عرض تفاح", "qty": 0.96, "price": 70.0, "total": 67.2 },
+                { "name": "تفاح", "qty": 1.95, "price": 30.0, "total": 58.5 },
+                { "name": "خيار", "qty": 1.02, "price": 25.0, "total": 25.5 },
+                { "name": "ليمون بلدي", "qty": 0.44, "price": 30.0, "total": 13.2 },
+                { "name": "بطاطا", "qty": 2.16, "price": 20.0, "total": 43.2 },
+                { "name": "ربطة جرجير", "qty": 4.0, "price": 3.0, "total": 12.0 },
+                { "name": "نعناع فريش", "qty": 1.0, "price": 5.0, "total": 5.0 },
+                { "name": "بسكوت بسكرم 24 قطعه", "qty": 5, "price": 12.5, "total": 62.5 },
+                { "name": "بسكوت شوفان 30 قطعه", "qty": 7, "price": 18.75, "total": 131.25 },
+                { "name": "كوكاكولا لمون نعناع 250 جم", "qty": 25, "price": 40.0, "total": 1000.0 }
+            ],
+            "total": 1418.35,
+            "footer_lines": [
+                { "text": "دمياط الجديدة - المركزية - مقابل البنك الأهلي القديم" },
+                { "text": "خدمة توصيل للمنازل ٢٤ ساعة" },
+                { "text": "01533333161 - 01533333262" }
+            ]
+        }))
+        .expect("sample receipt")
+    }
+
+    /// Runs `f` with the bundled fonts set up for `layout`.
+    fn with_typesetter<R>(layout: &Layout, f: impl FnOnce(&Typesetter) -> R) -> R {
+        let assets = Assets::load(None, layout).expect("fonts");
+        f(&assets.typesetter(layout).expect("typesetter"))
+    }
+
+    /// FNV-1a over the dimensions and pixels; stable across platforms and runs.
+    fn image_hash(img: &GrayImage) -> String {
+        let mut h: u64 = 0xcbf29ce484222325;
+        let dims = [img.width().to_le_bytes(), img.height().to_le_bytes()].concat();
+        for b in dims.iter().chain(img.as_raw().iter()) {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+        format!("{:016x}", h)
+    }
+
+    /// Bounding box (min_x, min_y, max_x, max_y) of the dark pixels.
+    fn ink_bbox(img: &RgbImage) -> Option<(u32, u32, u32, u32)> {
+        let mut bbox: Option<(u32, u32, u32, u32)> = None;
+        for (x, y, Rgb([r, _, _])) in img.enumerate_pixels() {
+            if *r < 128 {
+                let b = bbox.get_or_insert((x, y, x, y));
+                *b = (b.0.min(x), b.1.min(y), b.2.max(x), b.3.max(y));
+            }
+        }
+        bbox
+    }
+
+    /// A printer that records what it is sent and answers status requests from a script.
+    struct ScriptedPrinter {
+        written: Rc<std::cell::RefCell<Vec<u8>>>,
+        replies: std::cell::RefCell<VecDeque<u8>>,
+    }
+    impl Driver for ScriptedPrinter {
+        fn name(&self) -> String { "scripted".into() }
+        fn write(&self, data: &[u8]) -> escpos::errors::Result<()> {
+            self.written.borrow_mut().extend_from_slice(data);
+            Ok(())
+        }
+        fn read(&self, buf: &mut [u8]) -> escpos::errors::Result<usize> {
+            match self.replies.borrow_mut().pop_front() {
+                Some(b) => { buf[0] = b; Ok(1) }
+                None => Ok(0),
+            }
+        }
+        fn flush(&self) -> escpos::errors::Result<()> { Ok(()) }
+    }
+    fn scripted(replies: &[u8]) -> BoxedDriver {
+        scripted_log(replies).0
+    }
+    /// `scripted`, plus a handle on everything the job writes to it.
+    fn scripted_log(replies: &[u8]) -> (BoxedDriver, Rc<std::cell::RefCell<Vec<u8>>>) {
+        let written = Rc::<std::cell::RefCell<Vec<u8>>>::default();
+        let printer = ScriptedPrinter { written: written.clone(), replies: std::cell::RefCell::new(replies.iter().copied().collect()) };
+        (BoxedDriver(Box::new(printer)), written)
+    }
+
+    // ---------------- Golden image ----------------
+
+    /// Any intentional rendering change needs a re-bless:
+    /// `RECEIPT_BLESS=1 cargo test golden_receipt`, then commit the new hash.
+    #[test]
+    fn golden_receipt() {
+        let img = render_receipt(&sample_receipt(), &Layout::default()).expect("render");
+        let actual = image_hash(&img);
+        let golden = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/receipt_default.fnv");
+        if std::env::var_os("RECEIPT_BLESS").is_some() {
+            std::fs::create_dir_all(golden.parent().unwrap()).unwrap();
+            std::fs::write(&golden, format!("{}\n", actual)).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&golden)
+            .unwrap_or_else(|e| panic!("golden hash {}: {} (bless with RECEIPT_BLESS=1)", golden.display(), e));
+        assert_eq!(actual, expected.trim(), "receipt bitmap changed; re-bless if intended");
+    }
+
+    /// `cargo test --features test-output sample_pngs` writes representative receipts to
+    /// `target/test-output/*.png`, to eyeball after layout changes.
+    #[cfg(feature = "test-output")]
+    #[test]
+    fn sample_pngs() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("target/test-output");
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample = sample_receipt();
+        let mut long_names = sample_receipt();
+        long_names.store_name = "اسواق ومخابز ابو عمر للمواد الغذائية\nفرع دمياط الجديدة - المنطقة المركزية".into();
+        long_names.items[0].name = "عرض تفاح أحمر لبناني مستورد درجة أولى وزن ٢ كيلو".into();
+        long_names.items[7].name = "Biscuits Biscream 24 pcs بسكوت بسكرم 24 قطعه".into();
+        let mut discounted = sample_receipt();
+        discounted.discount = 18.35;
+        discounted.tax_rate = 0.14;
+        discounted.items[1].discount = Some(3.5);
+        discounted.amount_paid = Some(1700.0);
+        discounted.payment_method = Some("نقدي".into());
+        let cases = [
+            ("default", &sample, Layout::default()),
+            ("arabic_digits", &sample, Layout { number_locale: NumberLocale::ArabicIndic, thousands_separator: true, ..Layout::default() }),
+            ("long_names", &long_names, Layout::default()),
+            ("discount_tax_payment", &discounted, Layout::default()),
+            ("58mm", &sample, Layout::for_width(384)),
+            ("itemized_tax", &discounted, Layout { render_mode: RenderMode::ItemizedTax, ..Layout::default() }),
+            ("kitchen", &sample, Layout::default().kitchen()),
+        ];
+        for (name, data, layout) in cases {
+            let img = render_receipt(data, &layout).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            img.save(dir.join(format!("{}.png", name))).unwrap();
+        }
+    }
+
+    // ---------------- Shaping and bidi ----------------
+
+    #[test]
+    fn shaping_skips_text_without_arabic() {
+        let layout = Layout::default();
+        with_typesetter(&layout, |ts| {
+            for latin in ["+20 100 123 4567", "Coca Cola 250ml", "INV-2024/0001", ""] {
+                assert_eq!(ts.shape(latin), latin);
+            }
+            for arabic in ["بسكوت بسكرم 24 قطعه", "إجمالي الفاتورة", "لا"] {
+                assert_eq!(ts.shape(arabic), ts.reshaper.reshape(arabic));
+                assert_ne!(ts.shape(arabic), arabic);
+            }
+        });
+    }
+
+    #[test]
+    fn mixed_name_stays_in_name_column() {
+        let layout = Layout::default();
+        with_typesetter(&layout, |ts| {
+            let scale = PxScale::from(layout.fonts.item);
+
+            let (left, right) = layout.margins();
+            let r_name = layout.paper_width_px as i32 - right;
+            let inner_w = r_name - left;
+            let r_qty = r_name - (inner_w as f32 * layout.cols[0]) as i32;
+
+            let mut img: RgbImage = ImageBuffer::from_pixel(layout.paper_width_px, 80, Rgb([255, 255, 255]));
+            draw_mixed_rtl_right(&mut img, ts, scale, "جبنه رومي وزن ٢٥٠جم", r_name, 0);
+            let (min_x, _, max_x, _) = ink_bbox(&img).expect("ink");
+            assert!(max_x as i32 <= r_name, "ink past the right edge: {}", max_x);
+            assert!(min_x as i32 >= r_qty, "ink spills into the qty column: {} < {}", min_x, r_qty);
+        });
+    }
+
+    #[test]
+    fn ltr_run_sits_left_of_rtl_words() {
+        let layout = Layout::default();
+        with_typesetter(&layout, |ts| {
+            let scale = PxScale::from(layout.fonts.item);
+            let right = 500;
+            let blank = || -> RgbImage { ImageBuffer::from_pixel(576, 80, Rgb([255, 255, 255])) };
+
+            // Logical "بسكوت 24": the Arabic word reads first, so it must be drawn rightmost,
+            // pixel-identical to the word alone, with the digits entirely to its left.
+            let word_w = measure_mixed(ts, scale, "بسكوت");
+            let mut mixed = blank();
+            draw_mixed_rtl_right(&mut mixed, ts, scale, "بسكوت 24", right, 0);
+            let mut word = blank();
+            draw_mixed_rtl_right(&mut word, ts, scale, "بسكوت", right, 0);
+
+            for y in 0..80 {
+                for x in (right - word_w) as u32..576 {
+                    assert_eq!(mixed.get_pixel(x, y), word.get_pixel(x, y), "word region differs at ({}, {})", x, y);
+                }
+            }
+            let (min_x, _, _, _) = ink_bbox(&mixed).expect("ink");
+            assert!((min_x as i32) < right - word_w, "digits missing left of the word");
+        });
+    }
+
+    #[test]
+    fn forced_ltr_name_keeps_its_own_order() {
+        let layout = Layout::default();
+        with_typesetter(&layout, |ts| {
+            let scale = PxScale::from(layout.fonts.item);
+            let blank = || -> RgbImage { ImageBuffer::from_pixel(576, 80, Rgb([255, 255, 255])) };
+            let name = "250 Coca Cola";
+            let mut item: Item = serde_json::from_value(serde_json::json!({ "name": name, "qty": 1, "price": 1.0, "total": 1.0 })).unwrap();
+            let (mut mixed, mut named, mut ltr) = (blank(), blank(), blank());
+            draw_name(&mut named, ts, scale, &item, name, 500, 10);
+            draw_mixed_rtl_right(&mut mixed, ts, scale, name, 500, 10);
+            assert!(named == mixed, "default stays bidi-ordered");
+            item.force_ltr = true;
+            let mut forced = blank();
+            draw_name(&mut forced, ts, scale, &item, name, 500, 10);
+            draw_ltr_right(&mut ltr, ts, scale, name, 500, 10);
+            assert!(forced == ltr);
+            assert!(forced != mixed, "the leading size moves to the end in an RTL paragraph");
+        });
+    }
+
+    #[test]
+    fn char_spacing_widens_only_unjoined_letters() {
+        let spaced = Layout { char_spacing: 3, ..Layout::default() };
+        with_typesetter(&Layout::default(), |plain| with_typesetter(&spaced, |wide| {
+            let scale = PxScale::from(spaced.fonts.item);
+            let extra = |s: &str| measure_mixed(wide, scale, s) - measure_mixed(plain, scale, s);
+            assert_eq!(extra("بيت"), 0); // joined all the way
+            assert_eq!(extra("باب"), 3); // alef doesn't join the final beh
+            assert_eq!(extra("1418.35"), 0);
+            let mut a: RgbImage = ImageBuffer::from_pixel(300, 60, Rgb([255, 255, 255]));
+            draw_mixed_rtl_right(&mut a, wide, scale, "باب", 290, 5);
+            let (x0, _, x1, _) = ink_bbox(&a).unwrap();
+            assert!(x1 <= 290 && 290 - x0 as i32 <= measure_mixed(wide, scale, "باب") + 2, "{}..{}", x0, x1);
+        }));
+    }
+
+    #[test]
+    fn missing_glyphs_are_replaced_and_reported() {
+        let mut data = sample_receipt();
+        data.items[1].name = "تفاح 😀".into();
+        let layout = Layout::default();
+        with_typesetter(&layout, |ts| {
+            let (fixed, missing) = cover_missing(&data, &mut layout.clone(), ts);
+            assert_eq!(missing, vec!['😀']);
+            assert_eq!(fixed.items[1].name.chars().count(), 6);
+            assert!(fixed.items[1].name.chars().all(|c| ts.covers(c)), "{}", fixed.items[1].name);
+            assert_eq!(missing_glyph_warning(&missing).as_deref(), Some("no glyph for U+1F600 '😀'"));
+            let mut labelled = Layout { weight_label: Some("kg ☃".into()), copy_captions: vec!["نسخة 🧾".into()], ..layout.clone() };
+            let (_, missing) = cover_missing(&sample_receipt(), &mut labelled, ts);
+            assert_eq!(missing, vec!['☃', '🧾']);
+            assert!(labelled.weight_label.as_deref().is_some_and(|l| l.chars().all(|c| ts.covers(c))));
+        });
+        let labelled = Layout { invoice_label: Some("No. ☃".into()), ..layout };
+        let body = job_body(&sample_receipt(), &labelled).expect("body");
+        assert_eq!(body.warnings, vec!["no glyph for U+2603 '☃'".to_string()]);
+    }
+
+    // ---------------- Amounts and items ----------------
+
+    #[test]
+    fn amounts_follow_number_locale() {
+        assert_eq!(format_amount(1234.5, 2, NumberLocale::Western, false), "1234.50");
+        assert_eq!(format_amount(1234.5, 2, NumberLocale::Western, true), "1,234.50");
+        assert_eq!(format_amount(-5.0, 2, NumberLocale::Western, true), "-5.00");
+        assert_eq!(format_amount(1234.5, 2, NumberLocale::ArabicIndic, true), "١٬٢٣٤٫٥٠");
+        assert_eq!(format_amount(1234.5, 0, NumberLocale::Western, true), "1,234");
+        assert_eq!(format_amount(0.125, 3, NumberLocale::Western, false), "0.125");
+    }
+
+    #[test]
+    fn totals_spell_out_in_arabic() {
+        assert_eq!(amount_to_arabic_words(250.0, "ج.م"), "فقط مائتان وخمسون جنيهًا لا غير");
+        assert_eq!(amount_to_arabic_words(3.0, "EGP"), "فقط ثلاثة جنيهات لا غير");
+        assert_eq!(
+            amount_to_arabic_words(1418.35, "جنيه"),
+            "فقط ألف وأربعمائة وثمانية عشر جنيهًا وخمسة وثلاثون قرشًا لا غير"
+        );
+        assert_eq!(amount_to_arabic_words(12000.5, ""), "فقط اثنا عشر ألفًا و50/100 لا غير");
+    }
+
+    #[test]
+    fn timestamp_formats_like_the_sample_header() {
+        let fmt = DateTimeFormat { utc_offset_minutes: Some(120), ..DateTimeFormat::default() };
+        assert_eq!(fmt.format(1762222140).as_deref(), Some("٤ نوفمبر - ٤:٠٩ صباحا"));
+        let fmt = DateTimeFormat { hour12: false, arabic_months: false, digits: NumberLocale::Western, ..fmt };
+        assert_eq!(fmt.format(1762222140).as_deref(), Some("4/11 - 04:09"));
+    }
+
+    #[test]
+    fn line_tax_overrides_receipt_rate() {
+        let mut data = sample_receipt();
+        data.items.truncate(2); // 67.2 + 58.5
+        data.tax_rate = 0.14;
+        data.items[1].tax_rate = Some(0.0);
+        assert!((data.tax_amount() - 67.2 * 0.14).abs() < 0.001);
+        assert!((data.grand_total() - (67.2 * 1.14 + 58.5)).abs() < 0.001);
+    }
+
+    #[test]
+    fn quantities_print_per_unit() {
+        let item = |qty: &str, unit| Item {
+            name: "تفاح".into(), qty_str: qty.into(), price: 30.0, total: 58.5,
+            discount: None, tax_rate: None, notes: Vec::new(), unit, sku: None, force_ltr: false,
+        };
+        assert_eq!(item("1.00", Some(Unit::Piece)).qty_text(None, None), "1");
+        assert_eq!(item("1.95", Some(Unit::Weight)).qty_text(None, Some("كجم")), "1.95 كجم");
+        assert_eq!(item("2", Some(Unit::Weight)).qty_text(None, None), "2.00");
+        assert_eq!(item("0.25", Some(Unit::Weight)).qty_text(Some(3), None), "0.250");
+        assert_eq!(item("1.0", None).qty_text(None, Some("kg")), "1.0");
+        assert_eq!(item("1.5", None).qty_text(Some(3), None), "1.500");
+        assert_eq!(item("½", Some(Unit::Piece)).qty_text(None, None), "½");
+    }
+
+    #[test]
+    fn items_sort_in_arabic_dictionary_order() {
+        let data = sample_receipt();
+        let names = |sort| -> Vec<String> {
+            ordered_items(&data, &Layout { sort, ..Layout::default() }).iter().map(|it| it.name.clone()).collect()
+        };
+        let by_name = names(ItemSort::ByName);
+        assert_eq!(by_name[..4], ["بسكوت بسكرم 24 قطعه", "بسكوت شوفان 30 قطعه", "بطاطا", "تفاح"]);
+        assert!(by_name.iter().position(|n| n == "تفاح") < by_name.iter().position(|n| n == "خيار"));
+        assert_eq!(names(ItemSort::ByValueDesc)[0], "كوكاكولا لمون نعناع 250 جم");
+        assert_eq!(names(ItemSort::None)[0], "عرض تفاح");
+        assert_eq!(arabic_sort_key("أَحمد"), arabic_sort_key("احمد"));
+    }
+
+    // ---------------- Receipt layout ----------------
+
+    #[test]
+    fn right_margin_keeps_ink_off_the_clipped_edge() {
+        let layout = Layout { margin_right: Some(8), ..Layout::default() };
+        let img = render_receipt(&sample_receipt(), &layout).expect("render");
+        let edge = img.width() - 8;
+        let inked = img.enumerate_pixels().find(|(x, _, p)| *x >= edge && p.0[0] < 128);
+        assert!(inked.is_none(), "ink inside the right margin at {:?}", inked.map(|(x, y, _)| (x, y)));
+    }
+
+    #[test]
+    fn long_title_shrinks_to_the_paper() {
+        let layout = Layout::default();
+        with_typesetter(&layout, |ts| {
+            let w = layout.paper_width_px as i32;
+            assert_eq!(title_scale(ts, &layout.fonts, "اسواق ابو عمر", w).y, layout.fonts.title);
+            let long = "اسواق ومخابز ابو عمر للمواد الغذائية";
+            let scale = title_scale(ts, &layout.fonts, long, w);
+            assert!(scale.y < layout.fonts.title && scale.y >= layout.fonts.title_min);
+            assert!(measure_mixed(ts, scale, long) <= w || scale.y == layout.fonts.title_min);
+        });
+    }
+
+    #[test]
+    fn store_info_replaces_the_free_form_title() {
+        let mut data = sample_receipt();
+        data.store = Some(serde_json::from_value(serde_json::json!({
+            "name": "اسواق ابو عمر", "tax_id": "123-456-789", "commercial_registry": " ", "branch": "دمياط الجديدة"
+        })).unwrap());
+        data.store_name = "غير مستخدم".into();
+        assert_eq!(data.title(), "اسواق ابو عمر");
+        assert_eq!(data.store.as_ref().unwrap().lines(), ["الفرع: دمياط الجديدة", "رقم التسجيل الضريبي: 123-456-789"]);
+        let metrics = measure_receipt(&data, &Layout::default()).expect("measure");
+        assert_eq!(metrics.rows.iter().filter(|r| r.row == "store_info").count(), 2);
+    }
+
+    #[test]
+    fn reprint_stamp_heads_the_receipt() {
+        let data = ReceiptData { watermark: Some("نسخة مكررة".into()), ..sample_receipt() };
+        let metrics = measure_receipt(&data, &Layout::default()).expect("measure");
+        assert_eq!(metrics.rows[0].row, "watermark");
+        let plain = measure_receipt(&sample_receipt(), &Layout::default()).expect("measure");
+        assert!(metrics.height_px > plain.height_px);
+        let blank = ReceiptData { watermark: Some("  ".into()), ..sample_receipt() };
+        assert_eq!(measure_receipt(&blank, &Layout::default()).unwrap().height_px, plain.height_px);
+    }
+
+    #[test]
+    fn labeled_invoice_number_can_sit_right() {
+        let data = sample_receipt();
+        let row_ink = |layout: &Layout| {
+            let metrics = measure_receipt(&data, layout).expect("measure");
+            let top = metrics.rows.iter().find(|r| r.row == "invoice_no").unwrap().y.max(0) as u32;
+            let bottom = metrics.rows.iter().find(|r| r.row == "columns").unwrap().y as u32;
+            let img = render_receipt(&data, layout).expect("render");
+            let xs: Vec<u32> = img.enumerate_pixels().filter(|(_, y, p)| (top..bottom).contains(y) && p.0[0] < 128).map(|(x, _, _)| x).collect();
+            (*xs.iter().min().unwrap(), *xs.iter().max().unwrap())
+        };
+        let label = Some("رقم الفاتورة".to_string());
+        let right = Layout { invoice_label: label.clone(), invoice_align: Align::Right, ..Layout::default() };
+        let right_edge = (right.paper_width_px as i32 - right.margins().1) as u32;
+        assert!(row_ink(&right).1 + 4 >= right_edge, "{:?}", row_ink(&right));
+        let (l, r) = row_ink(&Layout { invoice_label: label, ..Layout::default() });
+        let centre = right.paper_width_px / 2;
+        assert!(l < centre && r > centre && r + 40 < right_edge, "{}..{}", l, r);
+    }
+
+    #[test]
+    fn empty_cart_prints_a_message_or_is_rejected() {
+        let data = ReceiptData { items: Vec::new(), total: 99.0, ..sample_receipt() };
+        assert_eq!(data.grand_total(), 0.0);
+        let metrics = measure_receipt(&data, &Layout::default()).expect("measure");
+        assert!(metrics.rows.iter().any(|r| r.row == "no items"));
+        let reject = Layout { empty_items: EmptyItems::Reject, ..Layout::default() };
+        assert_eq!(render_receipt(&data, &reject).unwrap_err(), "receipt has no items");
+        assert!(render_text_mode(&data, &reject).is_err());
+    }
+
+    #[test]
+    fn text_mode_falls_back_to_raster_for_what_it_cannot_print() {
+        let data: ReceiptData = serde_json::from_value(serde_json::json!({
+            "store_name": "Corner Shop",
+            "invoice_no": "42",
+            "items": [{ "name": "Tea", "qty": 1, "price": 2.0, "total": 2.0 }],
+            "total": 2.0
+        }))
+        .expect("ascii receipt");
+        let text = Layout { render_mode: RenderMode::Text, ..Layout::default() };
+        assert!(text_mode_supported(&data, &text));
+        assert!(job_body(&data, &text).expect("body").height.is_none());
+        let arabic_label = Layout { weight_label: Some("كجم".into()), ..text.clone() };
+        assert!(!text_mode_supported(&data, &arabic_label));
+        assert!(job_body(&data, &arabic_label).expect("body").height.is_some());
+        assert!(!text_mode_supported(&data, &Layout { invoice_label: Some("رقم الفاتورة".into()), ..text.clone() }));
+        let spec = BarcodeSpec { symbology: Symbology::Code128, height: 40, module_width: 2, render: BarcodeRenderMode::Bitmap };
+        assert!(!text_mode_supported(&ReceiptData { barcode: Some(spec), ..data }, &text));
+    }
+
+    #[test]
+    fn sku_prints_under_the_name_only_when_present() {
+        let mut data = sample_receipt();
+        data.items[1].sku = Some("6221031490019".into());
+        data.items[4].sku = Some("  ".into());
+        let plain = measure_receipt(&data, &Layout::default()).expect("measure");
+        let layout = Layout { show_sku: true, ..Layout::default() };
+        let with_sku = measure_receipt(&data, &layout).expect("measure");
+        assert_eq!(plain.columns.iter().map(|c| (c.left, c.right)).collect::<Vec<_>>(),
+                   with_sku.columns.iter().map(|c| (c.left, c.right)).collect::<Vec<_>>());
+        let gap = |m: &ReceiptMetrics, i: usize| m.rows[m.rows.iter().position(|r| r.row == format!("item {}", i)).unwrap() + 1].y
+            - m.rows.iter().find(|r| r.row == format!("item {}", i)).unwrap().y;
+        assert!(gap(&with_sku, 2) > gap(&plain, 2));
+        assert_eq!(gap(&with_sku, 5), gap(&plain, 5)); // blank SKU: no line
+        assert_eq!(with_sku.height_px - plain.height_px, (gap(&with_sku, 2) - gap(&plain, 2)) as u32);
+    }
+
+    #[test]
+    fn row_separators_grow_the_receipt() {
+        let data = sample_receipt();
+        let plain = render_receipt(&data, &Layout::default()).expect("render");
+        let ruled = Layout { row_separator: Some(SeparatorStyle::Solid), ..Layout::default() };
+        let ruled = render_receipt(&data, &ruled).expect("render");
+        assert!(ruled.height() > plain.height(), "{} <= {}", ruled.height(), plain.height());
+    }
+
+    #[test]
+    fn metrics_match_the_rendered_receipt() {
+        let data = sample_receipt();
+        for supersample in [1, 2] {
+            let layout = Layout { supersample, ..Layout::default() };
+            let metrics = measure_receipt(&data, &layout).expect("measure");
+            let img = render_receipt(&data, &layout).expect("render");
+            assert_eq!((metrics.width_px, metrics.height_px), img.dimensions());
+            assert_eq!(metrics.columns.iter().map(|c| c.column).collect::<Vec<_>>(), ["name", "qty", "price", "total"]);
+            assert_eq!(metrics.columns[0].right, layout.paper_width_px as i32 - layout.margins().1);
+            assert!(metrics.columns.windows(2).all(|w| w[0].left == w[1].right), "columns must tile at supersample {}", supersample);
+            assert!(metrics.columns.iter().all(|c| c.left >= 0 && c.right <= img.width() as i32), "columns in output px");
+            assert_eq!(metrics.rows.iter().filter(|r| r.row.starts_with("item ")).count(), data.items.len());
+            assert!(metrics.rows.windows(2).all(|w| w[0].y <= w[1].y));
+            assert_eq!(metrics.separators.len(), 1);
+        }
+    }
+
+    #[test]
+    fn item_index_column_sits_right_of_the_name() {
+        let data = sample_receipt();
+        for supersample in [1, 2] {
+            let layout = Layout { show_item_index: true, number_locale: NumberLocale::ArabicIndic, supersample, ..Layout::default() };
+            let metrics = measure_receipt(&data, &layout).expect("measure");
+            assert_eq!(metrics.columns.iter().map(|c| c.column).collect::<Vec<_>>(), ["index", "name", "qty", "price", "total"]);
+            assert_eq!(metrics.columns[0].right, layout.paper_width_px as i32 - layout.margins().1);
+            assert!(metrics.columns.windows(2).all(|w| w[0].left == w[1].right), "columns must tile at supersample {}", supersample);
+            let plain = measure_receipt(&data, &Layout { supersample, ..Layout::default() }).expect("measure");
+            assert!(metrics.columns[1].right - metrics.columns[1].left < plain.columns[0].right - plain.columns[0].left);
+        }
+    }
+
+    #[test]
+    fn sixty_items_keep_the_whole_footer() {
+        let mut data = sample_receipt();
+        data.items = data.items.iter().cycle().take(60).cloned().collect();
+        data.total = data.items.iter().map(|i| i.total).sum();
+        let layout = Layout::default();
+        let metrics = measure_receipt(&data, &layout).expect("measure");
+        let img = render_receipt(&data, &layout).expect("render");
+        assert_eq!(metrics.rows.iter().filter(|r| r.row.starts_with("item ")).count(), 60);
+        let last = metrics.rows.iter().rfind(|r| r.row.starts_with("footer ")).expect("footer row");
+        let bottom = last.y + layout.fonts.footer_phones as i32; // the last footer line is the phone numbers
+        assert!(bottom <= img.height() as i32, "footer ends at {} past the {} px image", bottom, img.height());
+        let inked = (last.y as u32..bottom as u32).any(|y| (0..img.width()).any(|x| img.get_pixel(x, y).0[0] < 128));
+        assert!(inked, "last footer line missing");
+    }
+
+    // ---------------- Barcodes and QR ----------------
+
+    #[test]
+    fn bitmap_barcode_keeps_its_quiet_zones() {
+        let spec = BarcodeSpec { symbology: Symbology::Ean13, height: 40, module_width: 2, render: BarcodeRenderMode::Bitmap };
+        let modules = barcode_modules(&spec, "4006381333931").expect("ean13");
+        assert_eq!(modules.len(), 95);
+        assert_eq!(&modules[..3], &[1, 0, 1]);
+        let mut img: RgbImage = ImageBuffer::from_pixel(300, 50, Rgb([255, 255, 255]));
+        draw_barcode(&mut img, &modules, 2, 40, 0, 300, 0).expect("fits");
+        let (x0, _, x1, _) = ink_bbox(&img).expect("bars");
+        assert!(x0 >= BARCODE_QUIET_MODULES as u32 * 2 && 299 - x1 >= BARCODE_QUIET_MODULES as u32 * 2, "{}..{}", x0, x1);
+        let mut narrow: RgbImage = ImageBuffer::from_pixel(200, 50, Rgb([255, 255, 255]));
+        assert!(draw_barcode(&mut narrow, &modules, 2, 40, 0, 200, 0).is_err());
+    }
+
+    #[test]
+    fn native_qr_sets_model_size_and_ec_before_store_and_print() {
+        let spec: QrSpec = serde_json::from_value(serde_json::json!({ "native": true, "ec": "H", "module_size": 5 })).unwrap();
+        let out = qr_command(&spec, "https://e.example/i/1").unwrap();
+        let at = |f: &[u8]| out.windows(f.len()).position(|w| w == f).unwrap();
+        let model = at(&[0x1D, 0x28, 0x6B, 0x04, 0x00, 0x31, 0x41, 50]);
+        let size = at(&[0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x43, 5]);
+        let ec = at(&[0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x45, 51]);
+        let store = at(&[0x1D, 0x28, 0x6B, 24, 0x00, 0x31, 0x50, 0x30]);
+        let print = at(&[0x1D, 0x28, 0x6B, 0x03, 0x00, 0x31, 0x51, 0x30]);
+        assert!(model < size && size < ec && ec < store && store < print);
+        // Model 1 stops at version 14: 486 bytes at L, 207 at H; model 2 goes further
+        let m1 = |ec| QrSpec { model: 1, ec, ..spec.clone() };
+        assert!(qr_command(&m1(QrEc::L), &"x".repeat(486)).is_ok());
+        assert!(qr_command(&m1(QrEc::L), &"x".repeat(487)).unwrap_err().contains("does not fit a model 1"));
+        assert!(qr_command(&m1(QrEc::H), &"x".repeat(207)).is_ok());
+        assert!(qr_command(&m1(QrEc::H), &"x".repeat(208)).is_err());
+        assert!(qr_command(&QrSpec { model: 2, ..m1(QrEc::H) }, &"x".repeat(208)).is_ok());
+        assert!(qr_command(&QrSpec { model: 3, ..spec }, "x").is_err());
+    }
+
+    // ---------------- Images and raster ----------------
+
+    #[test]
+    fn images_are_binarized_before_the_text_threshold() {
+        let layout = Layout::from_json_detailed(r#"{"text_threshold": 150, "image_threshold": 90}"#).expect("layout");
+        assert_eq!((layout.threshold, layout.logo_threshold), (150, Some(90)));
+        let ramp = GrayImage::from_fn(64, 24, |x, _| Luma([(x * 4) as u8]));
+        let logo = binarize_logo(ramp.clone(), &layout);
+        assert!(logo.pixels().all(|p| p.0[0] == 0 || p.0[0] == 255));
+        assert_eq!(pack_esc_star_24(&logo, 0, 30), pack_esc_star_24(&logo, 0, 220));
+        // without an image threshold everything goes through the text threshold, as before
+        assert_eq!(binarize_logo(ramp.clone(), &Layout::default()), ramp);
+    }
+
+    #[test]
+    fn graphics_go_out_in_strips() {
+        let mut gray = GrayImage::from_pixel(64, 300, Luma([255]));
+        for y in (0..10).chain(250..260) { gray.put_pixel(5, y, Luma([0])); }
+        let strips = graphics_chunks(&gray, 150);
+        assert_eq!(strips.len(), 2); // 256 + 44 rows
+        assert_eq!(&strips[0][..5], &[0x1D, 0x28, 0x4C, 0x0A, 0x08]); // p = 10 + 8 * 256
+        assert!(strips[0].ends_with(&[0x1D, 0x28, 0x4C, 0x02, 0x00, 0x30, 0x32]));
+        assert_eq!(&strips[1][..5], &[0x1D, 0x28, 0x4C, 0x6A, 0x01]); // p = 10 + 8 * 44
+    }
+
+    #[test]
+    fn red_rows_go_out_between_color_commands() {
+        let mut image = GrayImage::from_pixel(8, 96, Luma([255]));
+        for y in 30..40 { image.put_pixel(2, y, Luma([0])); }
+        let layout = Layout { band_feed: Some(0), ..Layout::default() };
+        let red = std::iter::once(30..40).collect();
+        let rendered = Rendered { image, splices: Vec::new(), missing: Vec::new(), red };
+        let chunks = rendered_chunks(&rendered, &layout);
+        let red = chunks.iter().position(|c| *c == COLOR_RED).expect("ESC r 1");
+        assert_eq!(chunks[red + 2], COLOR_BLACK); // one 24-dot band of red in between
+        assert_eq!(chunks.len(), 2 + 3 + 3); // bands: 0..30, red 30..40, 40..96
+    }
+
+    // ---------------- Job bytes ----------------
+
+    #[test]
+    fn line_spacing_is_set_and_restored() {
+        assert_eq!(line_spacing_bytes(&Layout::default()), (Vec::new(), Vec::new()));
+        let layout = Layout { line_spacing_dots: Some(24), ..Layout::default() };
+        assert_eq!(line_spacing_bytes(&layout), (vec![0x1B, 0x33, 24], vec![0x1B, 0x32]));
+    }
+
+    #[test]
+    fn tear_off_feeds_instead_of_cutting() {
+        let layout: Layout = serde_json::from_str(r#"{"cut_mode": {"tear_off": {"feed_dots": 300}}}"#).unwrap();
+        assert_eq!(cut_bytes(&layout), vec![0x1B, 0x4A, 255, 0x1B, 0x4A, 45]);
+        let layout: Layout = serde_json::from_str(r#"{"cut_mode": "partial"}"#).unwrap();
+        assert_eq!(cut_bytes(&layout), vec![0x1D, 0x56, 0x01]);
+    }
+
+    #[test]
+    fn stub_is_split_off_by_a_partial_cut() {
+        let mut data = sample_receipt();
+        data.stub = Some(StubData { lines: vec!["خصم ١٠٪ على زيارتك القادمة".into()], qr: Some("PROMO10".into()) });
+        let rendered = compose_receipt(&data, &Layout::default()).expect("render");
+        let cut = rendered.splices.iter().find(|sp| sp.bytes.ends_with(&[0x1D, 0x56, 0x01])).expect("partial cut");
+        let plain = render_receipt(&sample_receipt(), &Layout::default()).expect("render");
+        assert!(cut.row >= plain.height() - Layout::default().margin_bottom as u32);
+        assert!(rendered.image.height() > cut.row);
+    }
+
+    #[test]
+    fn cp864_line_is_visual_order() {
+        assert!(CP864.windows(2).all(|w| w[0].0 < w[1].0), "CP864 must stay sorted");
+        let reshaper = ReshapeConfig::default().reshaper();
+        assert_eq!(cp864_line(&reshaper, "لا 12"), b"12 \x9D");
+    }
+
+    // ---------------- Sending ----------------
+
+    #[test]
+    fn paper_out_aborts_or_waits_for_a_reload() {
+        let parts: Vec<JobPart> = (0..12).map(|i| (format!("band {}", i), Cow::Owned(vec![0xAA; 4]))).collect();
+        // DLE EOT 2 / 4 replies: 0x12 = all well, 0x32 = stopped at paper end
+        let (driver, written) = scripted_log(&[0x12, 0x12, 0x32, 0x12]);
+        let err = write_job(&driver, &parts, &[0x1D, 0x56, 0x01], PaperCheck::Abort, &AtomicBool::new(false)).unwrap_err();
+        assert_eq!(err, "out of paper at band 8 after 32 of 48 bytes (partial print)");
+        assert!(written.borrow().ends_with(&[0x1D, 0x56, 0x01]), "the printed part is still cut");
+        let driver = scripted(&[0x12, 0x12, 0x32, 0x12, 0x12, 0x12]); // reloaded on the first retry
+        assert_eq!(write_job(&driver, &parts, &[], PaperCheck::Wait { timeout_ms: 5000 }, &AtomicBool::new(false)), Ok(48));
+        let driver = scripted(&[]); // never answers: printed without further checks
+        assert_eq!(write_job(&driver, &parts, &[], PaperCheck::Abort, &AtomicBool::new(false)), Ok(48));
+        let forever = Layout { paper_check: PaperCheck::Wait { timeout_ms: MAX_PAPER_WAIT_MS + 1 }, ..Layout::default() };
+        assert!(forever.validate().unwrap_err().contains("paper_check.timeout_ms"));
+    }
+
+    #[test]
+    fn cancel_stops_only_the_flagged_device() {
+        let parts: Vec<JobPart> = (0..3).map(|i| (format!("band {}/3", i + 1), Cow::Owned(vec![0xAA; 4]))).collect();
+        let (a, b) = (cancel_flag("test-printer-a"), cancel_flag("test-printer-b"));
+        assert!(Arc::ptr_eq(&a, &cancel_flag("test-printer-a")));
+        a.store(true, Ordering::SeqCst);
+        assert_eq!(write_job(&scripted(&[]), &parts, &[], PaperCheck::Off, &b), Ok(12));
+        assert!(a.load(Ordering::SeqCst), "another device's job leaves the flag alone");
+        let err = write_job(&scripted(&[]), &parts, &[0x1D, 0x56, 0x01], PaperCheck::Off, &a).unwrap_err();
+        assert_eq!(err, "cancelled at band 1/3 after 0 band(s), 0 of 12 bytes");
+        assert!(!a.load(Ordering::SeqCst), "the cancelled job consumes its flag");
+    }
+
+    /// A queued job cancelled while its bytes are in flight: the dump is a FIFO, so the
+    /// job blocks on the full pipe until the reader, having set the flag, drains it.
+    #[cfg(unix)]
+    #[test]
+    fn queued_job_cancelled_mid_stream_stops_at_a_band_and_cuts() {
+        use std::io::Read;
+        let fifo = std::env::temp_dir().join(format!("receipt-cancel-{}.fifo", std::process::id()));
+        let _ = std::fs::remove_file(&fifo);
+        assert!(std::process::Command::new("mkfifo").arg(&fifo).status().expect("mkfifo").success());
+        std::env::set_var("PRINTER_DUMP_PATH", &fifo);
+        let target = Target::Network { host: "127.0.0.1".into(), port: 9 }; // refused: the dump is the only output
+        let mut data = sample_receipt();
+        data.items = data.items.iter().cycle().take(60).cloned().collect();
+        let layout = Layout::default();
+
+        let flag = cancel_flag(&target.label());
+        let reader = std::thread::spawn({
+            let fifo = fifo.clone();
+            move || {
+                let mut file = std::fs::File::open(&fifo).expect("open fifo");
+                let mut dump = vec![0; 4096];
+                file.read_exact(&mut dump).expect("first bytes");
+                flag.store(true, Ordering::SeqCst); // the turn has started, so the flag sticks
+                file.read_to_end(&mut dump).expect("rest");
+                dump
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().expect("runtime");
+        let err = runtime.block_on(print_queued(&data, &layout, &target)).map(|r| r.message).unwrap_err();
+        let dump = reader.join().expect("reader");
+        std::env::remove_var("PRINTER_DUMP_PATH");
+        let _ = std::fs::remove_file(&fifo);
+
+        assert!(err.starts_with("cancelled at copy 1, band "), "{}", err);
+        let bands: usize = err.split(" after ").nth(1).and_then(|s| s.split(' ').next()).and_then(|n| n.parse().ok()).expect("band count");
+        let body = job_body(&data, &layout).expect("body");
+        assert!(bands < body.chunks.len());
+        let (spacing, default_spacing) = line_spacing_bytes(&layout);
+        let expected = [
+            vec![0x1B, 0x40],
+            density_bytes(&layout),
+            spacing,
+            body.chunks[..bands].concat(),
+            default_spacing,
+            feed_before_cut(&layout),
+            cut_bytes(&layout),
+        ]
+        .concat();
+        assert!(dump == expected, "dump of {} bytes is not {} whole band(s) plus the cut", dump.len(), bands);
+    }
+
+    // ---------------- PDF ----------------
+
+    #[test]
+    fn run_length_round_trips() {
+        let data: Vec<u8> = [vec![0xFF; 300], vec![1, 2, 3, 3, 4], (0..=200).collect()].concat();
+        let encoded = run_length(&data);
+        let mut decoded = Vec::new();
+        let mut i = 0;
+        loop {
+            let n = encoded[i] as usize;
+            match n {
+                128 => break,
+                0..=127 => { decoded.extend_from_slice(&encoded[i + 1..i + 2 + n]); i += n + 2; }
+                _ => { decoded.extend(vec![encoded[i + 1]; 257 - n]); i += 2; }
+            }
+        }
+        assert_eq!(decoded, data);
+        assert!(encoded.len() < data.len());
+    }
+
+    #[test]
+    fn pdf_page_is_paper_width() {
+        let gray = GrayImage::from_pixel(576, 1152, Luma([255]));
+        let pdf = String::from_utf8_lossy(&receipt_pdf(&gray, &Layout::default())).into_owned();
+        assert!(pdf.starts_with("%PDF-"));
+        let media_box = pdf.split("/MediaBox [").nth(1).and_then(|r| r.split(']').next()).expect("MediaBox");
+        let sides: Vec<f32> = media_box.split_whitespace().map(|n| n.parse().unwrap()).collect();
+        assert!((sides[2] - 226.77).abs() < 0.01 && (sides[3] - 453.54).abs() < 0.01, "{:?}", sides);
+        assert!(pdf.trim_end().ends_with("%%EOF"));
+    }
+
+    #[test]
+    fn pdf_xref_offsets_point_at_their_objects() {
+        let mut gray = GrayImage::from_pixel(64, 40, Luma([255]));
+        for x in 0..30 { gray.put_pixel(x, 7, Luma([0])); }
+        let pdf = receipt_pdf(&gray, &Layout::default());
+        let at = pdf.windows(9).rposition(|w| w == b"startxref").expect("startxref");
+        let tail = std::str::from_utf8(&pdf[at..]).unwrap();
+        let xref: usize = tail.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let table = std::str::from_utf8(&pdf[xref..at]).unwrap();
+        assert!(table.starts_with("xref"));
+        let entries: Vec<&str> = table.lines().skip(3).filter(|l| l.ends_with(" n") || l.ends_with(" n ")).collect();
+        assert_eq!(entries.len(), 5);
+        for (i, entry) in entries.iter().enumerate() {
+            let offset: usize = entry[..10].parse().unwrap();
+            let header = format!("{} 0 obj", i + 1);
+            assert!(pdf[offset..].starts_with(header.as_bytes()), "object {} is not at {}", i + 1, offset);
+        }
+    }
+
+    // ---------------- Layout presets ----------------
+
+    #[test]
+    fn layout_presets_round_trip_and_refuse_bad_names() {
+        let dir = std::env::temp_dir().join(format!("pos-presets-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(list_presets(&dir).unwrap(), Vec::<String>::new());
+        let saved = save_preset(&dir, "كاشير 2", r#"{"paper_width_px": 384}"#, false).expect("save");
+        assert_eq!(load_preset(&dir, "كاشير 2").unwrap(), saved);
+        assert_eq!(Layout::from_json_detailed(&saved).ok().map(|l| l.paper_width_px), Some(384));
+        assert!(save_preset(&dir, "كاشير 2", "{}", false).unwrap_err().contains("already exists"));
+        save_preset(&dir, "كاشير 2", "{}", true).expect("overwrite");
+        for bad in ["", "../etc", "a/b", "x.json"] {
+            assert!(save_preset(&dir, bad, "{}", false).is_err(), "{:?}", bad);
+        }
+        assert!(save_preset(&dir, "wide", r#"{"threshold": "x"}"#, false).is_err());
+        assert_eq!(list_presets(&dir).unwrap(), ["كاشير 2"]);
+        assert!(load_preset(&dir, "missing").unwrap_err().contains("no preset"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }